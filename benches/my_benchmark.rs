@@ -2,11 +2,6 @@ use cosmic_system::simulation::{create_bodies, CreateBodiesResult, UpdateBodies}
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
 pub fn criterion_benchmark(c: &mut Criterion) {
-    let bounding_box = cosmic_system::bounding_box::BoundingBox::new(
-        glam::DVec3::ONE * -4.0 * cosmic_system::simulation::AU,
-        glam::DVec3::ONE * 4.0 * cosmic_system::simulation::AU,
-    );
-
     c.bench_function("update_bodies", |b| {
         let CreateBodiesResult {
             cosmic_system,
@@ -15,12 +10,7 @@ pub fn criterion_benchmark(c: &mut Criterion) {
             ..
         } = create_bodies(1001);
 
-        let mut update_bodies = UpdateBodies {
-            bounding_box,
-            cosmic_system,
-            forces: vec![],
-            movements,
-        };
+        let mut update_bodies = UpdateBodies::new(cosmic_system, &bodies, movements, 1.0);
 
         b.iter(|| {
             for _ in 0..100 {
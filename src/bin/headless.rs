@@ -0,0 +1,370 @@
+//! Runs a simulation without a window, and writes snapshots and diagnostics to disk.
+//!
+//! `headless --scenario plummer --bodies 1000 --steps 100 --dt 3600 --output out`
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    process::ExitCode,
+    str::FromStr,
+};
+
+use cosmic_system::{
+    block_timesteps::BlockTimesteps,
+    checkpoint,
+    diagnostics::{Diagnostics, Drift},
+    export::{ExportFormat, Exporter},
+    gadget::{GadgetFormat, GadgetSnapshot, GadgetUnits},
+    integrator::{IntegratorKind, LeapfrogKdk, Rk4, SemiImplicitEuler, VelocityVerlet, Yoshida4},
+    simulation::{CreateBodiesResult, Scenario, UpdateBodies},
+    snapshot::{Snapshot, SnapshotFields},
+    softening::Softening,
+};
+
+const USAGE: &str = "Usage: headless [options]
+
+Options:
+  --scenario <name>           two-clusters, plummer or cold-collapse (default: plummer)
+  --gadget <file>             Starts from a GADGET snapshot instead of a scenario
+  --gadget-units <name>       Internal units of the GADGET files: galactic (kpc, 1e10 solar masses, km/s),
+                              solar-system (AU, solar masses, km/s) or si (default: galactic)
+  --gadget-snapshots          Also writes the snapshots in GADGET format 2
+  --bodies <count>            Number of bodies (default: 1000)
+  --steps <count>             Number of steps (default: 100)
+  --dt <seconds>              Timestep (default: 3600)
+  --integrator <name>         euler, leapfrog, verlet, rk4, yoshida or block (default: leapfrog)
+  --max-level <level>         Deepest level of block, with steps of dt / 2^level, below 32 (default: 8)
+  --eta <value>               Accuracy of the timesteps of block (default: 0.025)
+  --timestep-length <m>       Length scale of the timesteps of block (default: the softening length)
+  --theta <angle>             Barnes-Hut opening angle, 0 for direct summation (default: 1)
+  --softening <m>             Plummer softening length (default: none)
+  --output <directory>        Where the files go (default: output)
+  --snapshot-every <steps>    0 for only the last step (default: 10)
+  --diagnostics-every <steps> (default: 10)
+  --export <format>           Also writes the bodies as csv or npy (default: none)
+  --export-every <steps>      (default: 10)
+  --export-key                Includes the Morton keys in the export
+  --checkpoint-every <steps>  Overwrites checkpoint.ckpt in the output, 0 for never (default: 0)
+  --resume <file>             Continues a checkpoint up to --steps, with the settings it got saved with.
+                              The drift in diagnostics.csv is then relative to where it resumed.
+  --help                      Print this";
+
+struct Options {
+    scenario: Scenario,
+    gadget: Option<PathBuf>,
+    gadget_units: GadgetUnits,
+    gadget_snapshots: bool,
+    bodies: usize,
+    steps: u64,
+    dt: f64,
+    integrator: IntegratorKind,
+    max_level: u8,
+    eta: f64,
+    timestep_length: Option<f64>,
+    theta: f64,
+    softening: Softening,
+    output: PathBuf,
+    snapshot_every: u64,
+    diagnostics_every: u64,
+    export: Option<ExportFormat>,
+    export_every: u64,
+    export_key: bool,
+    checkpoint_every: u64,
+    resume: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            scenario: Scenario::Plummer,
+            gadget: None,
+            gadget_units: GadgetUnits::GALACTIC,
+            gadget_snapshots: false,
+            bodies: 1000,
+            steps: 100,
+            dt: 3600.0,
+            integrator: IntegratorKind::LeapfrogKdk(LeapfrogKdk::default()),
+            max_level: 8,
+            eta: 0.025,
+            timestep_length: None,
+            theta: 1.0,
+            softening: Softening::None,
+            output: PathBuf::from("output"),
+            snapshot_every: 10,
+            diagnostics_every: 10,
+            export: None,
+            export_every: 10,
+            export_key: false,
+            checkpoint_every: 0,
+            resume: None,
+        }
+    }
+}
+
+/// `Ok(None)` if the help got requested
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    fn value<T: FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+        let value = value.ok_or_else(|| format!("{} needs a value", name))?;
+        value
+            .parse()
+            .map_err(|_| format!("Invalid value for {}: {:?}", name, value))
+    }
+
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scenario" => options.scenario = value(&arg, args.next())?,
+            "--gadget" => options.gadget = Some(value(&arg, args.next())?),
+            "--gadget-units" => {
+                let name: String = value(&arg, args.next())?;
+                options.gadget_units = match name.as_str() {
+                    "galactic" => GadgetUnits::GALACTIC,
+                    "solar-system" => GadgetUnits::SOLAR_SYSTEM,
+                    "si" => GadgetUnits::SI,
+                    _ => return Err(format!("Unknown units {:?}", name)),
+                };
+            }
+            "--gadget-snapshots" => options.gadget_snapshots = true,
+            "--bodies" => options.bodies = value(&arg, args.next())?,
+            "--steps" => options.steps = value(&arg, args.next())?,
+            "--dt" => options.dt = value(&arg, args.next())?,
+            "--integrator" => {
+                let name: String = value(&arg, args.next())?;
+                options.integrator = match name.as_str() {
+                    "euler" => IntegratorKind::SemiImplicitEuler(SemiImplicitEuler),
+                    "leapfrog" => IntegratorKind::LeapfrogKdk(LeapfrogKdk::default()),
+                    "verlet" => IntegratorKind::VelocityVerlet(VelocityVerlet::default()),
+                    "rk4" => IntegratorKind::Rk4(Rk4),
+                    "yoshida" => IntegratorKind::Yoshida4(Yoshida4),
+                    // The settings get filled in once all options are known
+                    "block" => IntegratorKind::BlockTimesteps(BlockTimesteps::new(0.0, 0)),
+                    _ => return Err(format!("Unknown integrator {:?}", name)),
+                };
+            }
+            "--max-level" => options.max_level = value(&arg, args.next())?,
+            "--eta" => options.eta = value(&arg, args.next())?,
+            "--timestep-length" => options.timestep_length = Some(value(&arg, args.next())?),
+            "--theta" => options.theta = value(&arg, args.next())?,
+            "--softening" => {
+                options.softening = Softening::Plummer {
+                    epsilon: value(&arg, args.next())?,
+                }
+            }
+            "--output" => options.output = value(&arg, args.next())?,
+            "--snapshot-every" => options.snapshot_every = value(&arg, args.next())?,
+            "--diagnostics-every" => options.diagnostics_every = value(&arg, args.next())?,
+            "--export" => options.export = Some(value(&arg, args.next())?),
+            "--export-every" => options.export_every = value(&arg, args.next())?,
+            "--export-key" => options.export_key = true,
+            "--checkpoint-every" => options.checkpoint_every = value(&arg, args.next())?,
+            "--resume" => options.resume = Some(value(&arg, args.next())?),
+            "--help" | "-h" => return Ok(None),
+            _ => return Err(format!("Unknown option {:?}", arg)),
+        }
+    }
+
+    if options.bodies == 0 {
+        return Err("--bodies has to be at least 1".to_string());
+    }
+    if options.dt.is_nan() || options.dt <= 0.0 {
+        return Err("--dt has to be positive".to_string());
+    }
+    if options.theta.is_nan() || options.theta < 0.0 {
+        return Err("--theta can't be negative".to_string());
+    }
+    if let IntegratorKind::BlockTimesteps(integrator) = &mut options.integrator {
+        if options.max_level >= 32 {
+            return Err("--max-level has to be less than 32".to_string());
+        }
+        if options.eta.is_nan() || options.eta <= 0.0 {
+            return Err("--eta has to be positive".to_string());
+        }
+        let timestep_length = match (options.timestep_length, options.softening) {
+            (Some(length), _) => length,
+            (None, Softening::Plummer { epsilon }) => epsilon,
+            (None, _) => {
+                return Err("--integrator block needs --timestep-length or --softening".to_string())
+            }
+        };
+        if timestep_length.is_nan() || timestep_length <= 0.0 {
+            return Err("--timestep-length has to be positive".to_string());
+        }
+        *integrator = BlockTimesteps::new(timestep_length, options.max_level);
+        integrator.eta = options.eta;
+    }
+    if options.diagnostics_every == 0 {
+        return Err("--diagnostics-every has to be at least 1".to_string());
+    }
+    if options.export_every == 0 {
+        return Err("--export-every has to be at least 1".to_string());
+    }
+    Ok(Some(options))
+}
+
+fn write_diagnostics(
+    file: &mut impl Write,
+    time: f64,
+    diagnostics: &Diagnostics,
+    drift: &Drift,
+) -> io::Result<()> {
+    writeln!(
+        file,
+        "{},{},{:e},{:e},{:e},{:e},{:e},{:e},{}",
+        drift.step,
+        time,
+        diagnostics.kinetic_energy,
+        diagnostics.potential_energy,
+        diagnostics.total_energy(),
+        drift.energy,
+        drift.momentum,
+        drift.angular_momentum,
+        // Empty without potential energy
+        diagnostics
+            .virial_ratio
+            .map_or(String::new(), |virial_ratio| format!("{:e}", virial_ratio))
+    )
+}
+
+fn run(options: &Options) -> io::Result<bool> {
+    fs::create_dir_all(&options.output)?;
+    let diagnostics_path = options.output.join("diagnostics.csv");
+    let (mut update_bodies, mut bodies, mut diagnostics_file) = match &options.resume {
+        Some(path) => {
+            let (update_bodies, bodies) = checkpoint::load(path)?;
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(diagnostics_path)?;
+            (update_bodies, bodies, BufWriter::new(file))
+        }
+        None => {
+            let CreateBodiesResult {
+                mut cosmic_system,
+                bodies,
+                movements,
+                ..
+            } = match &options.gadget {
+                Some(path) => GadgetSnapshot::load(path, &options.gadget_units)?.to_result(),
+                None => options.scenario.create(options.bodies),
+            };
+            cosmic_system.set_theta(options.theta);
+            cosmic_system.set_softening(options.softening);
+            let mut update_bodies =
+                UpdateBodies::new(cosmic_system, &bodies, movements, options.dt);
+            update_bodies.integrator = options.integrator.clone();
+
+            let mut file = BufWriter::new(File::create(diagnostics_path)?);
+            writeln!(
+                file,
+                "step,time,kinetic_energy,potential_energy,total_energy,energy_drift,momentum_drift,angular_momentum_drift,virial_ratio"
+            )?;
+            (update_bodies, bodies, file)
+        }
+    };
+
+    let exporter = options.export.map(|format| Exporter {
+        include_key: options.export_key,
+        ..Exporter::new(&options.output, format, options.export_every)
+    });
+
+    // A resumed run already wrote everything for its first step
+    let first_step = update_bodies.step;
+    let resumed = options.resume.is_some();
+    let initial = if resumed {
+        // On copies, because computing them rebuilds the tree, which would make the continuation differ
+        Diagnostics::compute(
+            &mut bodies.clone(),
+            &update_bodies.movements,
+            &update_bodies.forces,
+            &mut update_bodies.cosmic_system.clone(),
+        )
+    } else {
+        update_bodies.diagnostics(&mut bodies)
+    };
+    let mut last_drift = Drift::new(first_step, &initial, &initial);
+    for step in first_step..=options.steps {
+        if step > first_step {
+            update_bodies.update(&mut bodies);
+        } else if resumed {
+            continue;
+        }
+        let time = update_bodies.time;
+
+        if step % options.diagnostics_every == 0 || step == options.steps {
+            let diagnostics = update_bodies.diagnostics(&mut bodies);
+            last_drift = Drift::new(step, &initial, &diagnostics);
+            write_diagnostics(&mut diagnostics_file, time, &diagnostics, &last_drift)?;
+            println!("{}", last_drift);
+        }
+
+        let snapshot = if options.snapshot_every == 0 {
+            step == options.steps
+        } else {
+            step % options.snapshot_every == 0 || step == options.steps
+        };
+        if snapshot {
+            let path = options.output.join(format!("snapshot_{:06}.snap", step));
+            Snapshot::capture(&update_bodies, &bodies, SnapshotFields::ALL).save(path)?;
+            if options.gadget_snapshots {
+                let path = options.output.join(format!("snapshot_{:06}.gadget", step));
+                GadgetSnapshot::capture(&update_bodies, &bodies).save(
+                    path,
+                    &options.gadget_units,
+                    GadgetFormat::Two,
+                )?;
+            }
+        }
+        if let Some(exporter) = &exporter {
+            exporter.export(&update_bodies, &bodies)?;
+        }
+
+        if options.checkpoint_every != 0
+            && (step % options.checkpoint_every == 0 || step == options.steps)
+        {
+            // The diagnostics up to here have to survive, if the run gets killed before the next checkpoint
+            diagnostics_file.flush()?;
+            checkpoint::save(
+                &update_bodies,
+                &bodies,
+                options.output.join("checkpoint.ckpt"),
+            )?;
+        }
+    }
+    diagnostics_file.flush()?;
+
+    Ok(last_drift.energy.is_finite())
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    #[cfg(feature = "tracing")]
+    let _client = tracy_client::Client::start();
+
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => {
+            eprintln!("The simulation blew up, the energy isn't finite anymore");
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            eprintln!(
+                "Couldn't read the input or write to {}: {}",
+                options.output.display(),
+                error
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
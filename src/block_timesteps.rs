@@ -0,0 +1,149 @@
+use glam::DVec3;
+
+use crate::{
+    celestial_body::CelestialBody,
+    cosmic_system::CosmicSystem,
+    integrator::{compute_accelerations, compute_accelerations_where, drift, Integrator},
+    profiling::span,
+};
+
+/// Individual timesteps, organised in power of two blocks.
+///
+/// A body on level `l` takes steps of `dt / 2^l`, where `dt` is the step passed to [`Integrator::step`].
+/// Each body picks its level with the criterion `sqrt(2 * eta * softening_length / |a|)`,
+/// like in Aarseth's codes and GADGET-2.
+///
+/// Every substep, all bodies drift, but only the bodies whose step ends there get new forces.
+/// The kicks are done like in [`crate::integrator::LeapfrogKdk`].
+/// All bodies are synchronised again at the end of [`Integrator::step`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockTimesteps {
+    /// Accuracy parameter of the timestep criterion.
+    pub eta: f64,
+    /// Length scale of the timestep criterion in m, usually the gravitational softening length.
+    pub softening_length: f64,
+    /// The deepest level. Bodies on it take steps of `dt / 2^max_level`.
+    pub max_level: u8,
+    /// The level of every body, indexed by [`CelestialBody::index`].
+    pub levels: Vec<u8>,
+    /// Whether the levels and the accelerations match the current positions.
+    pub primed: bool,
+}
+
+impl BlockTimesteps {
+    pub fn new(softening_length: f64, max_level: u8) -> Self {
+        assert!(max_level < 32);
+        Self {
+            eta: 0.025,
+            softening_length,
+            max_level,
+            levels: vec![],
+            primed: false,
+        }
+    }
+
+    /// How many bodies are on each level, starting with level 0.
+    pub fn level_histogram(&self) -> Vec<usize> {
+        let mut histogram = vec![0; self.max_level as usize + 1];
+        for &level in &self.levels {
+            histogram[level as usize] += 1;
+        }
+        histogram
+    }
+
+    /// The level that the timestep criterion asks for.
+    fn wanted_level(&self, acceleration: DVec3, dt: f64) -> u8 {
+        let acceleration = acceleration.length();
+        if acceleration <= 0.0 {
+            return 0;
+        }
+        let wanted_dt = (2.0 * self.eta * self.softening_length / acceleration).sqrt();
+        let level = (dt / wanted_dt).log2().ceil();
+        level.clamp(0.0, self.max_level as f64) as u8
+    }
+
+    /// Substeps that a body on the given level takes per step.
+    #[inline]
+    fn stride(&self, level: u8) -> u32 {
+        (1u32 << self.max_level) >> level
+    }
+}
+
+impl Integrator for BlockTimesteps {
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    ) {
+        if !self.primed || self.levels.len() != bodies.len() {
+            compute_accelerations(cosmic_system, bodies, accelerations);
+            self.levels = vec![0; bodies.len()];
+            for body in bodies.iter() {
+                self.levels[body.index] = self.wanted_level(accelerations[body.index], dt);
+            }
+            self.primed = true;
+        }
+
+        let substeps = 1u32 << self.max_level;
+        let substep_dt = dt / substeps as f64;
+        let level_dt = |level: u8| dt / (1u32 << level) as f64;
+
+        // Opening half kick, everyone is synchronised here
+        for body in bodies.iter() {
+            let index = body.index;
+            velocities[index] += accelerations[index] * (0.5 * level_dt(self.levels[index]));
+        }
+
+        let mut substep = 0;
+        while substep < substeps {
+            // Skip ahead to the next substep where a body is active
+            let deepest_level = self.levels.iter().copied().max().unwrap_or(0);
+            let stride = self.stride(deepest_level);
+            let next_substep = (substep / stride + 1) * stride;
+            {
+                let _span = span!("Update bodies");
+                drift(
+                    bodies,
+                    velocities,
+                    (next_substep - substep) as f64 * substep_dt,
+                );
+            }
+            substep = next_substep;
+
+            let levels = &self.levels;
+            let is_active = |index: usize| substep % self.stride(levels[index]) == 0;
+            compute_accelerations_where(cosmic_system, bodies, accelerations, |body| {
+                is_active(body.index)
+            });
+
+            let active: Vec<usize> = bodies
+                .iter()
+                .map(|body| body.index)
+                .filter(|&index| is_active(index))
+                .collect();
+            for index in active {
+                // Closing half kick
+                let level = self.levels[index];
+                velocities[index] += accelerations[index] * (0.5 * level_dt(level));
+
+                let mut new_level = self.wanted_level(accelerations[index], dt);
+                if substep < substeps {
+                    // A longer step has to end on a substep where its level is synchronised
+                    while new_level < level && substep % self.stride(new_level) != 0 {
+                        new_level += 1;
+                    }
+                    // Opening half kick of the next step
+                    velocities[index] += accelerations[index] * (0.5 * level_dt(new_level));
+                }
+                self.levels[index] = new_level;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.primed = false;
+    }
+}
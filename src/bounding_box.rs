@@ -1,7 +1,6 @@
 use glam::DVec3;
 
-use crate::vec3_extensions::Vec3Extensions;
-
+/// Axis aligned box. The sides don't have to be equally long.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct BoundingBox {
     pub min: DVec3,
@@ -9,16 +8,140 @@ pub struct BoundingBox {
 }
 
 impl BoundingBox {
+    /// Contains nothing. The union of it with any other box is the other box.
+    pub const EMPTY: Self = Self {
+        min: DVec3::INFINITY,
+        max: DVec3::NEG_INFINITY,
+    };
+
     pub fn new(min: DVec3, max: DVec3) -> Self {
         Self { min, max }
     }
 
+    pub fn from_center(center: DVec3, half_extent: DVec3) -> Self {
+        Self::new(center - half_extent, center + half_extent)
+    }
+
+    /// Including the minimum, excluding the maximum, on every axis.
     pub fn contains(&self, point: DVec3) -> bool {
-        !point.all_less_than(self.min) && point.all_less_than(self.max)
+        point.cmpge(self.min).all() && point.cmplt(self.max).all()
+    }
+
+    /// Whether the other box is inside this one, including the sides.
+    /// Unlike [`BoundingBox::contains`], this also works for flat boxes.
+    pub fn contains_box(&self, other: &Self) -> bool {
+        self.min.cmple(other.min).all() && other.max.cmple(self.max).all()
+    }
+
+    #[inline]
+    pub fn center(&self) -> DVec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Length of the sides along every axis
+    #[inline]
+    pub fn extent(&self) -> DVec3 {
+        self.max - self.min
     }
 
+    /// Length of the longest side
     #[inline]
     pub fn side_length(&self) -> f64 {
-        self.max.x - self.min.x
+        self.extent().max_element()
+    }
+
+    /// Squared distance from the point to the closest point in the box, 0 if the point is inside
+    pub fn distance_squared(&self, point: DVec3) -> f64 {
+        (self.min - point)
+            .max(point - self.max)
+            .max(DVec3::ZERO)
+            .length_squared()
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    pub fn expand_to_include(&mut self, point: DVec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    /// `None` if the boxes don't overlap. Boxes that only touch have a flat intersection.
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        let intersection = Self::new(self.min.max(other.min), self.max.min(other.max));
+        intersection
+            .min
+            .cmple(intersection.max)
+            .all()
+            .then_some(intersection)
+    }
+
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.min.cmple(other.max).all() && other.min.cmple(self.max).all()
+    }
+
+    /// Where the ray `origin + t * direction` with `t >= 0` enters and leaves the box, as `(t_enter, t_exit)`.
+    /// `t_enter` is 0 if the origin is inside the box.
+    pub fn ray_intersection(&self, origin: DVec3, direction: DVec3) -> Option<(f64, f64)> {
+        // Slab test. Axes that the ray is parallel to give infinities, which min and max handle.
+        let inv_direction = direction.recip();
+        let t_min = (self.min - origin) * inv_direction;
+        let t_max = (self.max - origin) * inv_direction;
+        let t_enter = t_min.min(t_max).max_element().max(0.0);
+        let t_exit = t_min.max(t_max).min_element();
+        (t_enter <= t_exit).then_some((t_enter, t_exit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_box_operations() {
+        let a = BoundingBox::new(DVec3::ZERO, DVec3::new(4.0, 2.0, 1.0));
+        let b = BoundingBox::new(DVec3::ONE, DVec3::new(6.0, 3.0, 3.0));
+        assert_eq!(a.side_length(), 4.0);
+        assert_eq!(a.center(), DVec3::new(2.0, 1.0, 0.5));
+        assert_eq!(
+            a.union(&b),
+            BoundingBox::new(DVec3::ZERO, DVec3::new(6.0, 3.0, 3.0))
+        );
+        assert_eq!(BoundingBox::EMPTY.union(&a), a);
+        assert_eq!(
+            a.intersection(&b),
+            Some(BoundingBox::new(DVec3::ONE, DVec3::new(4.0, 2.0, 1.0)))
+        );
+        assert!(a.intersects(&b));
+        assert_eq!(a.distance_squared(DVec3::new(1.0, 1.0, 0.5)), 0.0);
+        assert_eq!(a.distance_squared(DVec3::new(7.0, 6.0, 0.5)), 25.0);
+
+        assert!(a.contains(DVec3::ZERO));
+        assert!(a.contains(DVec3::new(3.0, 1.0, 0.5)));
+        assert!(!a.contains(DVec3::new(4.0, 1.0, 0.5)));
+        // Outside on a single axis
+        assert!(!a.contains(DVec3::new(3.0, -1.0, 0.5)));
+        assert!(!a.contains(DVec3::new(3.0, 1.0, 2.0)));
+        assert!(a.contains_box(&a));
+        assert!(!a.contains_box(&b));
+        let flat = BoundingBox::new(DVec3::ONE, DVec3::new(3.0, 1.0, 1.0));
+        assert!(flat.contains_box(&flat));
+        assert!(a.contains_box(&flat));
+
+        let far_away = BoundingBox::new(DVec3::splat(10.0), DVec3::splat(11.0));
+        assert_eq!(a.intersection(&far_away), None);
+        assert!(!a.intersects(&far_away));
+
+        let mut c = BoundingBox::EMPTY;
+        c.expand_to_include(DVec3::ONE);
+        c.expand_to_include(-DVec3::ONE);
+        assert_eq!(c, BoundingBox::from_center(DVec3::ZERO, DVec3::ONE));
+
+        // Along the x axis, through the middle of the box
+        let origin = DVec3::new(-1.0, 1.0, 0.5);
+        assert_eq!(a.ray_intersection(origin, DVec3::X), Some((1.0, 5.0)));
+        assert_eq!(a.ray_intersection(origin, -DVec3::X), None);
+        assert_eq!(a.ray_intersection(a.center(), DVec3::Y), Some((0.0, 1.0)));
     }
 }
@@ -1,10 +1,18 @@
 use glam::DVec3;
 
+use crate::{bounding_box::BoundingBox, softening::Softening};
+
 #[derive(Clone, Copy, Debug)]
 pub struct CelestialBody {
+    /// Slot of the body in the per-body data, like [`crate::simulation::UpdateBodies::movements`].
+    /// Always in `0..n`, so it changes when another body gets removed.
     pub index: usize,
+    /// Never changes and never gets reused, unlike the index.
+    pub id: u64,
     pub position: DVec3,
     pub mass: f64,
+    /// Physical radius in m, for collisions. Bodies with a radius of 0 never collide.
+    pub radius: f64,
     pub key: u128,
 }
 
@@ -12,17 +20,28 @@ impl CelestialBody {
     pub fn new(index: usize, mass: f64, position: DVec3) -> Self {
         Self {
             index,
+            id: index as u64,
             mass,
             position,
+            radius: 0.0,
             key: 0,
         }
     }
 
+    /// The two bodies merged into one, at their center of mass and with their combined volume.
     pub fn from_objects(a: &CelestialBody, b: &CelestialBody) -> CelestialBody {
         let mass = a.mass + b.mass;
         assert!(mass > 0.0);
         let center_of_mass = (a.position * (a.mass / mass)) + (b.position * (b.mass / mass));
-        CelestialBody::new(0, mass, center_of_mass)
+        let mut merged = CelestialBody::new(0, mass, center_of_mass);
+        merged.radius = (a.radius.powi(3) + b.radius.powi(3)).cbrt();
+        merged
+    }
+
+    /// Box around the sphere of the body
+    #[inline]
+    pub fn bounds(&self) -> BoundingBox {
+        BoundingBox::from_center(self.position, DVec3::splat(self.radius))
     }
 
     #[inline]
@@ -32,18 +51,41 @@ impl CelestialBody {
 
     /// Assume that self has zero mass.
     pub fn gravitational_force_zero_mass(&self, other: &CelestialBody) -> DVec3 {
+        self.gravitational_force_zero_mass_softened(other, &Softening::None)
+    }
+
+    /// Assume that self has zero mass.
+    #[inline]
+    pub fn gravitational_force_zero_mass_softened(
+        &self,
+        other: &CelestialBody,
+        softening: &Softening,
+    ) -> DVec3 {
         if self.key == other.key {
             return DVec3::ZERO;
         }
 
         let delta = other.position - self.position;
-        let squared_distance = delta.length_squared();
-        let force = other.mass / (squared_distance * squared_distance.sqrt());
+        let force = other.mass * softening.force_factor(delta.length_squared());
         delta * force
     }
 
+    /// Gravitational potential of other at the position of self, without G.
+    #[inline]
+    pub fn gravitational_potential_zero_mass_softened(
+        &self,
+        other: &CelestialBody,
+        softening: &Softening,
+    ) -> f64 {
+        if self.key == other.key {
+            return 0.0;
+        }
+
+        -other.mass * softening.potential_factor(self.distance_to_squared(other))
+    }
+
     #[inline]
-    pub fn update(&mut self, current_movement: DVec3) {
-        self.position += current_movement;
+    pub fn update(&mut self, velocity: DVec3, dt: f64) {
+        self.position += velocity * dt;
     }
 }
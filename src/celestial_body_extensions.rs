@@ -1,4 +1,15 @@
-use comfy::Color;
+/// RGBA, each from 0 to 1
+pub type Color = [f32; 4];
+
+/// The colors of comfy
+pub const RED: Color = [0.90, 0.16, 0.22, 1.00];
+pub const BLUE: Color = [0.00, 0.47, 0.95, 1.00];
+pub const CYAN: Color = [0.0, 1.0, 1.0, 1.0];
+pub const MAGENTA: Color = [1.00, 0.00, 1.00, 1.00];
+pub const PINK: Color = [1.00, 0.43, 0.76, 1.00];
+pub const GREEN: Color = [0.00, 0.89, 0.19, 1.00];
+pub const DARK_GRAY: Color = [0.25, 0.25, 0.25, 1.0];
+pub const WHITE: Color = [1.00, 1.00, 1.00, 1.00];
 
 pub struct CelestialBodyDrawing {
     /// for drawing the body.
@@ -12,4 +23,10 @@ impl CelestialBodyDrawing {
     pub fn get_drawing_radius(&self) -> f32 {
         (self.radius.log10() * 0.02) as f32
     }
+
+    #[cfg(feature = "viewer")]
+    pub fn get_color(&self) -> comfy::Color {
+        let [r, g, b, a] = self.color;
+        comfy::Color::new(r, g, b, a)
+    }
 }
@@ -0,0 +1,496 @@
+//! The complete state of [`UpdateBodies`], so that a run can be continued later on
+//! exactly like it would have continued without the interruption.
+//!
+//! Unlike [`crate::snapshot`], this includes the settings of the tree and the integrator,
+//! the accelerations and the order of the bodies, which all influence the rounding of the next steps.
+//! The format is little-endian like the snapshots, but only meant to be read by the same version of this crate.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use glam::DVec3;
+
+use crate::{
+    block_timesteps::BlockTimesteps,
+    bounding_box::BoundingBox,
+    celestial_body::CelestialBody,
+    collision::CollisionMode,
+    cosmic_system::{BoundaryPolicy, CosmicSystem, MultipoleOrder},
+    integrator::{IntegratorKind, LeapfrogKdk, Rk4, SemiImplicitEuler, VelocityVerlet, Yoshida4},
+    opening_criterion::OpeningCriterion,
+    simulation::UpdateBodies,
+    snapshot::{invalid_data, read_f64, read_u32, read_u64, read_vec3, write_f64, write_vec3},
+    softening::Softening,
+};
+
+pub const MAGIC: [u8; 8] = *b"COSMCKPT";
+pub const VERSION: u32 = 2;
+
+/// More bodies than fit into memory, so larger capacities come from broken files
+const MAX_CAPACITY: usize = 1 << 32;
+
+pub fn write(
+    update_bodies: &UpdateBodies,
+    bodies: &[CelestialBody],
+    writer: &mut impl Write,
+) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+
+    let cosmic_system = &update_bodies.cosmic_system;
+    write_bounding_box(writer, cosmic_system.bounding_box())?;
+    write_u64(writer, cosmic_system.capacity() as u64)?;
+    write_u64(writer, cosmic_system.reserved_capacity() as u64)?;
+    let (tag, value) = match *cosmic_system.softening() {
+        Softening::None => (0, 0.0),
+        Softening::Plummer { epsilon } => (1, epsilon),
+        Softening::CubicSpline { h } => (2, h),
+        Softening::Parabolic { h } => (3, h),
+    };
+    write_tagged(writer, tag, value)?;
+    write_f64(writer, cosmic_system.theta())?;
+    let (tag, value) = match *cosmic_system.opening_criterion() {
+        OpeningCriterion::Geometric => (0, 0.0),
+        OpeningCriterion::Relative { alpha } => (1, alpha),
+        OpeningCriterion::CenterOfMassOffset => (2, 0.0),
+    };
+    write_tagged(writer, tag, value)?;
+    write_u8(
+        writer,
+        match cosmic_system.multipole_order() {
+            MultipoleOrder::Monopole => 0,
+            MultipoleOrder::Quadrupole => 1,
+        },
+    )?;
+    write_u8(
+        writer,
+        match cosmic_system.boundary_policy() {
+            BoundaryPolicy::Fixed => 0,
+            BoundaryPolicy::Grow => 1,
+            BoundaryPolicy::Fit => 2,
+        },
+    )?;
+
+    write_bounding_box(writer, &update_bodies.bounding_box)?;
+    write_f64(writer, update_bodies.dt)?;
+    write_f64(writer, update_bodies.time)?;
+    write_u64(writer, update_bodies.step)?;
+    write_u64(writer, update_bodies.next_id)?;
+    let (tag, value) = match update_bodies.collision_mode {
+        CollisionMode::None => (0, 0.0),
+        CollisionMode::Merge => (1, 0.0),
+        CollisionMode::Bounce { restitution } => (2, restitution),
+    };
+    write_tagged(writer, tag, value)?;
+    write_integrator(writer, &update_bodies.integrator)?;
+
+    // In the current order, because it decides the order of bodies with the same key in the tree
+    write_u64(writer, bodies.len() as u64)?;
+    for body in bodies {
+        write_u64(writer, body.index as u64)?;
+        write_u64(writer, body.id)?;
+        write_f64(writer, body.mass)?;
+        write_vec3(writer, body.position)?;
+        write_f64(writer, body.radius)?;
+        writer.write_all(&body.key.to_le_bytes())?;
+    }
+    write_vec3s(writer, &update_bodies.movements)?;
+    write_vec3s(writer, &update_bodies.forces)?;
+    Ok(())
+}
+
+pub fn read(reader: &mut impl Read) -> io::Result<(UpdateBodies, Vec<CelestialBody>)> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data("Not a checkpoint".to_string()));
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "Unsupported checkpoint version {}",
+            version
+        )));
+    }
+
+    let bounding_box = read_bounding_box(reader)?;
+    let capacity = read_u64(reader)?;
+    let reserved_capacity = read_u64(reader)?;
+    if capacity > MAX_CAPACITY as u64 || reserved_capacity > capacity {
+        return Err(invalid_data(format!(
+            "Invalid capacity {} with {} reserved",
+            capacity, reserved_capacity
+        )));
+    }
+    let capacity = capacity as usize;
+    let mut cosmic_system = CosmicSystem::new(bounding_box, capacity);
+    cosmic_system.set_reserved_capacity(reserved_capacity as usize);
+    cosmic_system.set_softening(match read_tagged(reader)? {
+        (0, _) => Softening::None,
+        (1, epsilon) => Softening::Plummer {
+            epsilon: non_negative("softening", epsilon)?,
+        },
+        (2, h) => Softening::CubicSpline {
+            h: non_negative("softening", h)?,
+        },
+        (3, h) => Softening::Parabolic {
+            h: non_negative("softening", h)?,
+        },
+        (tag, _) => return Err(unknown("softening", tag)),
+    });
+    cosmic_system.set_theta(non_negative("theta", read_f64(reader)?)?);
+    cosmic_system.set_opening_criterion(match read_tagged(reader)? {
+        (0, _) => OpeningCriterion::Geometric,
+        (1, alpha) => OpeningCriterion::Relative { alpha },
+        (2, _) => OpeningCriterion::CenterOfMassOffset,
+        (tag, _) => return Err(unknown("opening criterion", tag)),
+    });
+    cosmic_system.set_multipole_order(match read_u8(reader)? {
+        0 => MultipoleOrder::Monopole,
+        1 => MultipoleOrder::Quadrupole,
+        tag => return Err(unknown("multipole order", tag)),
+    });
+    cosmic_system.set_boundary_policy(match read_u8(reader)? {
+        0 => BoundaryPolicy::Fixed,
+        1 => BoundaryPolicy::Grow,
+        2 => BoundaryPolicy::Fit,
+        tag => return Err(unknown("boundary policy", tag)),
+    });
+
+    let mut update_bodies = UpdateBodies::new(cosmic_system, &[], vec![], 0.0);
+    update_bodies.bounding_box = read_bounding_box(reader)?;
+    update_bodies.dt = read_f64(reader)?;
+    update_bodies.time = read_f64(reader)?;
+    update_bodies.step = read_u64(reader)?;
+    update_bodies.next_id = read_u64(reader)?;
+    update_bodies.collision_mode = match read_tagged(reader)? {
+        (0, _) => CollisionMode::None,
+        (1, _) => CollisionMode::Merge,
+        (2, restitution) if (0.0..=1.0).contains(&restitution) => {
+            CollisionMode::Bounce { restitution }
+        }
+        (2, restitution) => {
+            return Err(invalid_data(format!("Invalid restitution {}", restitution)))
+        }
+        (tag, _) => return Err(unknown("collision mode", tag)),
+    };
+    update_bodies.integrator = read_integrator(reader)?;
+
+    let body_count = read_u64(reader)?;
+    let mut bodies = Vec::with_capacity(body_count.min(1 << 20) as usize);
+    for _ in 0..body_count {
+        let index = read_u64(reader)? as usize;
+        let id = read_u64(reader)?;
+        let mass = read_f64(reader)?;
+        let mut body = CelestialBody::new(index, mass, read_vec3(reader)?);
+        body.id = id;
+        body.radius = read_f64(reader)?;
+        let mut key = [0; 16];
+        reader.read_exact(&mut key)?;
+        body.key = u128::from_le_bytes(key);
+        bodies.push(body);
+    }
+    update_bodies.movements = read_vec3s(reader)?;
+    update_bodies.forces = read_vec3s(reader)?;
+    if capacity < bodies.len()
+        || update_bodies.movements.len() != bodies.len()
+        || !(update_bodies.forces.is_empty() || update_bodies.forces.len() == bodies.len())
+        || bodies.iter().any(|body| body.index >= bodies.len())
+    {
+        return Err(invalid_data("Broken checkpoint".to_string()));
+    }
+    Ok((update_bodies, bodies))
+}
+
+/// Writes to a temporary file first, so that a run that gets killed while saving keeps the previous checkpoint.
+pub fn save(
+    update_bodies: &UpdateBodies,
+    bodies: &[CelestialBody],
+    path: impl AsRef<Path>,
+) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&temporary_path)?);
+        write(update_bodies, bodies, &mut writer)?;
+        writer.into_inner()?.sync_all()?;
+    }
+    fs::rename(temporary_path, path)
+}
+
+pub fn load(path: impl AsRef<Path>) -> io::Result<(UpdateBodies, Vec<CelestialBody>)> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+fn write_integrator(writer: &mut impl Write, integrator: &IntegratorKind) -> io::Result<()> {
+    match integrator {
+        IntegratorKind::SemiImplicitEuler(_) => write_u8(writer, 0),
+        IntegratorKind::LeapfrogKdk(integrator) => {
+            write_u8(writer, 1)?;
+            write_u8(writer, integrator.primed as u8)
+        }
+        IntegratorKind::VelocityVerlet(integrator) => {
+            write_u8(writer, 2)?;
+            write_u8(writer, integrator.primed as u8)
+        }
+        IntegratorKind::Rk4(_) => write_u8(writer, 3),
+        IntegratorKind::Yoshida4(_) => write_u8(writer, 4),
+        IntegratorKind::BlockTimesteps(integrator) => {
+            write_u8(writer, 5)?;
+            write_f64(writer, integrator.eta)?;
+            write_f64(writer, integrator.softening_length)?;
+            write_u8(writer, integrator.max_level)?;
+            write_u8(writer, integrator.primed as u8)?;
+            write_u64(writer, integrator.levels.len() as u64)?;
+            writer.write_all(&integrator.levels)
+        }
+    }
+}
+
+fn read_integrator(reader: &mut impl Read) -> io::Result<IntegratorKind> {
+    Ok(match read_u8(reader)? {
+        0 => IntegratorKind::SemiImplicitEuler(SemiImplicitEuler),
+        1 => IntegratorKind::LeapfrogKdk(LeapfrogKdk {
+            primed: read_bool(reader)?,
+        }),
+        2 => IntegratorKind::VelocityVerlet(VelocityVerlet {
+            primed: read_bool(reader)?,
+        }),
+        3 => IntegratorKind::Rk4(Rk4),
+        4 => IntegratorKind::Yoshida4(Yoshida4),
+        5 => {
+            let eta = read_f64(reader)?;
+            let softening_length = read_f64(reader)?;
+            let max_level = read_u8(reader)?;
+            if max_level >= 32 {
+                return Err(invalid_data(format!("Invalid max level {}", max_level)));
+            }
+            let mut integrator = BlockTimesteps::new(softening_length, max_level);
+            integrator.eta = eta;
+            integrator.primed = read_bool(reader)?;
+            let mut levels = vec![0; read_u64(reader)? as usize];
+            reader.read_exact(&mut levels)?;
+            if let Some(level) = levels.iter().find(|&&level| level > max_level) {
+                return Err(invalid_data(format!("Invalid level {}", level)));
+            }
+            integrator.levels = levels;
+            IntegratorKind::BlockTimesteps(integrator)
+        }
+        tag => return Err(unknown("integrator", tag)),
+    })
+}
+
+fn unknown(what: &str, tag: u8) -> io::Error {
+    invalid_data(format!("Unknown {} {}", what, tag))
+}
+
+fn non_negative(what: &str, value: f64) -> io::Result<f64> {
+    if value.is_finite() && value >= 0.0 {
+        Ok(value)
+    } else {
+        Err(invalid_data(format!("Invalid {} {}", what, value)))
+    }
+}
+
+fn write_u8(writer: &mut impl Write, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+/// An enum with at most one number
+fn write_tagged(writer: &mut impl Write, tag: u8, value: f64) -> io::Result<()> {
+    write_u8(writer, tag)?;
+    write_f64(writer, value)
+}
+
+fn write_bounding_box(writer: &mut impl Write, bounding_box: &BoundingBox) -> io::Result<()> {
+    write_vec3(writer, bounding_box.min)?;
+    write_vec3(writer, bounding_box.max)
+}
+
+fn write_vec3s(writer: &mut impl Write, values: &[DVec3]) -> io::Result<()> {
+    write_u64(writer, values.len() as u64)?;
+    for &value in values {
+        write_vec3(writer, value)?;
+    }
+    Ok(())
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_bool(reader: &mut impl Read) -> io::Result<bool> {
+    match read_u8(reader)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        value => Err(invalid_data(format!("Invalid bool {}", value))),
+    }
+}
+
+fn read_tagged(reader: &mut impl Read) -> io::Result<(u8, f64)> {
+    Ok((read_u8(reader)?, read_f64(reader)?))
+}
+
+fn read_bounding_box(reader: &mut impl Read) -> io::Result<BoundingBox> {
+    Ok(BoundingBox::new(read_vec3(reader)?, read_vec3(reader)?))
+}
+
+fn read_vec3s(reader: &mut impl Read) -> io::Result<Vec<DVec3>> {
+    let count = read_u64(reader)?;
+    let mut values = Vec::with_capacity(count.min(1 << 20) as usize);
+    for _ in 0..count {
+        values.push(read_vec3(reader)?);
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulation::{create_plummer_sphere, AU};
+
+    use super::*;
+
+    #[test]
+    fn test_resume_is_bit_identical() {
+        let cases = [
+            (
+                IntegratorKind::LeapfrogKdk(LeapfrogKdk::default()),
+                CollisionMode::None,
+            ),
+            (
+                IntegratorKind::BlockTimesteps(BlockTimesteps::new(1e9, 3)),
+                CollisionMode::None,
+            ),
+            (
+                IntegratorKind::LeapfrogKdk(LeapfrogKdk::default()),
+                CollisionMode::Merge,
+            ),
+        ];
+        for (integrator, collision_mode) in cases {
+            let result = create_plummer_sphere(200);
+            let mut bodies = result.bodies;
+            if collision_mode == CollisionMode::Merge {
+                for body in &mut bodies {
+                    body.radius = 0.1 * AU;
+                }
+            }
+            let mut cosmic_system = result.cosmic_system;
+            cosmic_system.set_opening_criterion(OpeningCriterion::Relative { alpha: 0.005 });
+            cosmic_system.set_softening(Softening::Plummer { epsilon: 1e9 });
+            // Less than the tree needs, so that it shrinks again once most bodies are gone
+            cosmic_system.set_reserved_capacity(1);
+            let mut update_bodies =
+                UpdateBodies::new(cosmic_system, &bodies, result.movements, 36000.0);
+            update_bodies.integrator = integrator;
+            update_bodies.collision_mode = collision_mode;
+            let mut merged = 0;
+            for _ in 0..3 {
+                update_bodies.update(&mut bodies);
+                merged += update_bodies.events.len();
+            }
+
+            let mut bytes = vec![];
+            write(&update_bodies, &bodies, &mut bytes).unwrap();
+            let (mut resumed, mut resumed_bodies) = read(&mut bytes.as_slice()).unwrap();
+            assert_eq!(resumed.integrator, update_bodies.integrator);
+            assert_eq!(resumed.collision_mode, update_bodies.collision_mode);
+            assert_eq!(
+                resumed.cosmic_system.reserved_capacity(),
+                update_bodies.cosmic_system.reserved_capacity()
+            );
+
+            let capacity = update_bodies.cosmic_system.capacity();
+            for step in 0..10 {
+                if step == 5 {
+                    for id in 0..150 {
+                        update_bodies.despawn(&mut bodies, id);
+                        resumed.despawn(&mut resumed_bodies, id);
+                    }
+                }
+                update_bodies.update(&mut bodies);
+                resumed.update(&mut resumed_bodies);
+                assert_eq!(resumed.events.len(), update_bodies.events.len());
+                merged += update_bodies.events.len();
+            }
+            assert_eq!(collision_mode == CollisionMode::Merge, merged > 0);
+            assert!(update_bodies.cosmic_system.capacity() < capacity);
+            assert_eq!(
+                resumed.cosmic_system.capacity(),
+                update_bodies.cosmic_system.capacity()
+            );
+            assert_eq!(resumed.step, update_bodies.step);
+            assert_eq!(resumed.time, update_bodies.time);
+            assert_eq!(resumed.movements, update_bodies.movements);
+            assert_eq!(resumed.forces, update_bodies.forces);
+            assert_eq!(resumed_bodies.len(), bodies.len());
+            for (resumed_body, body) in resumed_bodies.iter().zip(&bodies) {
+                assert_eq!(resumed_body.id, body.id);
+                assert_eq!(resumed_body.position, body.position);
+                assert_eq!(resumed_body.mass, body.mass);
+            }
+        }
+    }
+
+    #[test]
+    fn test_broken_checkpoints() {
+        let result = create_plummer_sphere(10);
+        let mut bodies = result.bodies;
+        let mut update_bodies =
+            UpdateBodies::new(result.cosmic_system, &bodies, result.movements, 36000.0);
+        update_bodies.integrator = IntegratorKind::BlockTimesteps(BlockTimesteps::new(1e9, 3));
+        let cosmic_system = &mut update_bodies.cosmic_system;
+        cosmic_system.set_softening(Softening::Plummer { epsilon: 1e9 });
+        cosmic_system.set_reserved_capacity(1);
+        update_bodies.update(&mut bodies);
+
+        let mut too_few_forces = update_bodies.clone();
+        too_few_forces.forces.pop();
+        let mut too_deep = update_bodies.clone();
+        if let IntegratorKind::BlockTimesteps(integrator) = &mut too_deep.integrator {
+            integrator.levels[0] = 4;
+        }
+        let mut too_bouncy = update_bodies.clone();
+        too_bouncy.collision_mode = CollisionMode::Bounce { restitution: 2.0 };
+        let mut broken_files = vec![];
+        for broken in [&too_few_forces, &too_deep, &too_bouncy] {
+            let mut bytes = vec![];
+            write(broken, &bodies, &mut bytes).unwrap();
+            broken_files.push(bytes);
+        }
+
+        // Values that the setters don't accept, after the magic, the version and the bounding box
+        let mut bytes = vec![];
+        write(&update_bodies, &bodies, &mut bytes).unwrap();
+        let capacity = 12 + 48;
+        let reserved_capacity = capacity + 8;
+        let softening = reserved_capacity + 8 + 1;
+        let theta = softening + 8;
+        for (offset, value) in [
+            (capacity, (1u64 << 62).to_le_bytes()),
+            (capacity, 4u64.to_le_bytes()),
+            (reserved_capacity, (1u64 << 20).to_le_bytes()),
+            (softening, f64::NAN.to_le_bytes()),
+            (theta, (-1.0f64).to_le_bytes()),
+        ] {
+            let mut broken = bytes.clone();
+            broken[offset..offset + 8].copy_from_slice(&value);
+            broken_files.push(broken);
+        }
+
+        for bytes in broken_files {
+            let error = read(&mut bytes.as_slice()).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(read(&mut bytes.as_slice()).is_ok());
+    }
+}
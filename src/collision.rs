@@ -0,0 +1,269 @@
+use glam::DVec3;
+
+use crate::{celestial_body::CelestialBody, cosmic_system::CosmicSystem, simulation::Despawned};
+
+/// What happens when the spheres of two bodies overlap, see [`CelestialBody::radius`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CollisionMode {
+    /// Bodies fly through each other
+    #[default]
+    None,
+    /// Perfectly inelastic. The lighter body gets absorbed by the heavier one,
+    /// conserving mass, momentum and volume.
+    Merge,
+    /// Hard spheres that bounce off each other.
+    /// A restitution of 1 is perfectly elastic, and 0 makes them stick together while still being separate bodies.
+    Bounce { restitution: f64 },
+}
+
+impl CollisionMode {
+    /// [`CollisionMode::Bounce`], with a restitution from 0 to 1.
+    /// More than 1 would add energy with every bounce.
+    pub fn bounce(restitution: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&restitution),
+            "restitution: {}",
+            restitution
+        );
+        CollisionMode::Bounce { restitution }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum CollisionEvent {
+    /// The absorbed body got despawned.
+    /// Per-body data has to be updated in the same order as the events, see [`crate::simulation::UpdateBodies::despawn`].
+    Merged {
+        /// [`CelestialBody::id`] of the body that is left
+        survivor: u64,
+        absorbed: Despawned,
+    },
+    /// Ids of two bodies that bounced off each other
+    Bounced { a: u64, b: u64 },
+}
+
+/// Merges all bodies that overlap, including chains of overlapping bodies.
+/// Only updates the survivors, and returns the `(survivor, absorbed)` ids.
+/// The absorbed bodies still have to be removed.
+/// The bodies have to be the ones from the last [`CosmicSystem::set_all`], in the same order.
+pub fn merge_colliding(
+    cosmic_system: &CosmicSystem,
+    bodies: &mut [CelestialBody],
+    velocities: &mut [DVec3],
+) -> Vec<(u64, u64)> {
+    let mut pairs = cosmic_system.colliding_pairs(bodies);
+    // Same result, no matter how the threads were scheduled
+    pairs.sort_unstable();
+
+    // Position in bodies of the body that absorbed it, for every body
+    let mut absorbed_into: Vec<usize> = (0..bodies.len()).collect();
+    let find = |absorbed_into: &Vec<usize>, mut i: usize| {
+        while absorbed_into[i] != i {
+            i = absorbed_into[i];
+        }
+        i
+    };
+
+    let mut merged = vec![];
+    for (a, b) in pairs {
+        let a = find(&absorbed_into, a);
+        let b = find(&absorbed_into, b);
+        if a == b {
+            continue;
+        }
+        let (survivor, absorbed) =
+            if (bodies[a].mass, bodies[b].id) >= (bodies[b].mass, bodies[a].id) {
+                (a, b)
+            } else {
+                (b, a)
+            };
+
+        let survivor_body = bodies[survivor];
+        let absorbed_body = bodies[absorbed];
+        let momentum = velocities[survivor_body.index] * survivor_body.mass
+            + velocities[absorbed_body.index] * absorbed_body.mass;
+        let combined = CelestialBody::from_objects(&survivor_body, &absorbed_body);
+        velocities[survivor_body.index] = momentum / combined.mass;
+        let body = &mut bodies[survivor];
+        body.position = combined.position;
+        body.mass = combined.mass;
+        body.radius = combined.radius;
+
+        absorbed_into[absorbed] = survivor;
+        merged.push((survivor_body.id, absorbed_body.id));
+    }
+    merged
+}
+
+/// Bounces all overlapping bodies that move towards each other off each other, and returns their ids.
+/// Also pushes the overlapping bodies apart, without moving their center of mass, so that they don't sink into each other.
+/// The bodies have to be the ones from the last [`CosmicSystem::set_all`], in the same order.
+pub fn bounce_colliding(
+    cosmic_system: &CosmicSystem,
+    bodies: &mut [CelestialBody],
+    velocities: &mut [DVec3],
+    restitution: f64,
+) -> Vec<(u64, u64)> {
+    assert!(
+        (0.0..=1.0).contains(&restitution),
+        "restitution: {}",
+        restitution
+    );
+    let mut pairs = cosmic_system.colliding_pairs(bodies);
+    // Same result, no matter how the threads were scheduled
+    pairs.sort_unstable();
+
+    let mut bounced = vec![];
+    for (a, b) in pairs {
+        let (body_a, body_b) = (bodies[a], bodies[b]);
+        let delta = body_b.position - body_a.position;
+        let distance = delta.length();
+        if distance <= 0.0 {
+            // No direction to bounce in
+            continue;
+        }
+        let normal = delta / distance;
+        let inv_mass_a = 1.0 / body_a.mass;
+        let inv_mass_b = 1.0 / body_b.mass;
+        let inv_mass_sum = inv_mass_a + inv_mass_b;
+
+        let overlap = body_a.radius + body_b.radius - distance;
+        if overlap > 0.0 {
+            bodies[a].position -= normal * (overlap * inv_mass_a / inv_mass_sum);
+            bodies[b].position += normal * (overlap * inv_mass_b / inv_mass_sum);
+        }
+
+        let approach_speed = (velocities[body_a.index] - velocities[body_b.index]).dot(normal);
+        if approach_speed <= 0.0 {
+            // Already moving apart
+            continue;
+        }
+        let impulse = normal * ((1.0 + restitution) * approach_speed / inv_mass_sum);
+        velocities[body_a.index] -= impulse * inv_mass_a;
+        velocities[body_b.index] += impulse * inv_mass_b;
+        bounced.push((body_a.id, body_b.id));
+    }
+    bounced
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bounding_box::BoundingBox, simulation::UpdateBodies};
+
+    use super::*;
+
+    #[test]
+    fn test_merge_conserves_mass_and_momentum() {
+        let mut bodies = vec![
+            CelestialBody::new(0, 3.0, DVec3::new(0.0, 0.0, 0.0)),
+            CelestialBody::new(1, 1.0, DVec3::new(1.5, 0.0, 0.0)),
+            CelestialBody::new(2, 1.0, DVec3::new(3.0, 0.0, 0.0)),
+            CelestialBody::new(3, 1.0, DVec3::new(50.0, 0.0, 0.0)),
+        ];
+        for body in &mut bodies {
+            body.radius = 1.0;
+        }
+        let movements = vec![
+            DVec3::new(1.0, 0.0, 0.0),
+            DVec3::new(-1.0, 2.0, 0.0),
+            DVec3::new(0.0, 0.0, 4.0),
+            DVec3::ZERO,
+        ];
+        let momentum: DVec3 = bodies
+            .iter()
+            .map(|body| movements[body.index] * body.mass)
+            .sum();
+
+        let cosmic_system =
+            CosmicSystem::new(BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0), 4);
+        let mut update_bodies = UpdateBodies::new(cosmic_system, &bodies, movements, 1e-3);
+        update_bodies.collision_mode = CollisionMode::Merge;
+        update_bodies.update(&mut bodies);
+
+        // The chain of the first three bodies merged into the heaviest one
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(update_bodies.events.len(), 2);
+        let merged = bodies.iter().find(|body| body.id == 0).unwrap();
+        assert_eq!(merged.mass, 5.0);
+        assert!((merged.radius - 3.0f64.cbrt()).abs() < 1e-12);
+        let new_momentum: DVec3 = bodies
+            .iter()
+            .map(|body| update_bodies.movements[body.index] * body.mass)
+            .sum();
+        assert!(new_momentum.distance(momentum) < 1e-6, "{}", new_momentum);
+
+        // Per-body data stays in sync when the events get applied in order, like in the viewer
+        let mut ids: Vec<u64> = (0..4).collect();
+        for event in &update_bodies.events {
+            if let CollisionEvent::Merged { absorbed, .. } = event {
+                ids.swap_remove(absorbed.body.index);
+            }
+        }
+        for body in &bodies {
+            assert_eq!(ids[body.index], body.id);
+        }
+    }
+
+    #[test]
+    fn test_bounce() {
+        for (restitution, expected) in [(1.0, -1.0), (0.5, -0.5), (0.0, 0.0)] {
+            let mut bodies = vec![
+                CelestialBody::new(0, 2.0, DVec3::new(-0.9, 0.0, 0.0)),
+                CelestialBody::new(1, 2.0, DVec3::new(0.9, 0.0, 0.0)),
+            ];
+            for body in &mut bodies {
+                body.radius = 1.0;
+            }
+            let mut velocities = vec![DVec3::X, -DVec3::X];
+            let mut cosmic_system =
+                CosmicSystem::new(BoundingBox::new(DVec3::ONE * -10.0, DVec3::ONE * 10.0), 2);
+            cosmic_system.set_all(&mut bodies);
+
+            let bounced =
+                bounce_colliding(&cosmic_system, &mut bodies, &mut velocities, restitution);
+            assert_eq!(bounced, [(0, 1)]);
+            assert_eq!(velocities[0], DVec3::X * expected);
+            assert_eq!(velocities[1], -DVec3::X * expected);
+            // Pushed apart until they touch
+            assert!((bodies[0].position.distance(bodies[1].position) - 2.0).abs() < 1e-12);
+            assert_eq!(bodies[0].position + bodies[1].position, DVec3::ZERO);
+
+            // Not moving towards each other anymore, so they don't bounce again
+            cosmic_system.set_all(&mut bodies);
+            bodies[0].radius = 1.5;
+            let bounced =
+                bounce_colliding(&cosmic_system, &mut bodies, &mut velocities, restitution);
+            assert!(bounced.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_restitution() {
+        assert_eq!(
+            CollisionMode::bounce(0.5),
+            CollisionMode::Bounce { restitution: 0.5 }
+        );
+        for restitution in [-0.1, 1.5, f64::NAN] {
+            assert!(std::panic::catch_unwind(|| CollisionMode::bounce(restitution)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_zero_radius_never_collides() {
+        // The body without a radius sorts before the big one once, and after it once
+        for offset in [-0.5, 0.5] {
+            let mut bodies = vec![
+                CelestialBody::new(0, 1.0, DVec3::ZERO),
+                CelestialBody::new(1, 1.0, DVec3::splat(offset)),
+            ];
+            bodies[0].radius = 2.0;
+            let mut cosmic_system =
+                CosmicSystem::new(BoundingBox::new(DVec3::ONE * -10.0, DVec3::ONE * 10.0), 2);
+            cosmic_system.set_all(&mut bodies);
+            let position = bodies.iter().position(|body| body.radius == 0.0).unwrap();
+            assert_eq!(position, if offset < 0.0 { 0 } else { 1 });
+
+            assert!(cosmic_system.colliding_pairs(&bodies).is_empty());
+        }
+    }
+}
@@ -1,16 +1,19 @@
-use comfy::{
-    IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator, ParallelSliceMut,
+use glam::{DMat3, DVec3};
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
+    ParallelSliceMut,
 };
-use glam::DVec3;
+use std::{cmp::Reverse, collections::BinaryHeap};
 
 use crate::{
-    bounding_box::BoundingBox, celestial_body::CelestialBody, simulation, z_order::z_order_curve,
+    bounding_box::BoundingBox,
+    celestial_body::CelestialBody,
+    opening_criterion::OpeningCriterion,
+    simulation,
+    softening::Softening,
+    z_order::{z_order_curve, z_order_curve_inverse},
 };
 
-/// When distance/radius < T, then we can do that Barnes-Hut optimisation
-const T: f64 = 1.0;
-const INV_T_SQUARED: f64 = 1.0 / (T * T);
-
 #[derive(Clone)]
 pub struct CosmicSystem {
     bounding_box: BoundingBox,
@@ -19,9 +22,48 @@ pub struct CosmicSystem {
     /// Always a power of 2 size.
     /// See https://algorithmica.org/en/eytzinger
     nodes: Vec<CosmicSystemNode>,
+    /// The node array never automatically shrinks below this.
+    reserved_capacity: usize,
+    /// Used for the body-body interactions and for the approximated node interactions.
+    softening: Softening,
+    /// Opening angle. When width/distance < theta, then we can do that Barnes-Hut optimisation
+    theta: f64,
+    inv_theta_squared: f64,
+    opening_criterion: OpeningCriterion,
+    multipole_order: MultipoleOrder,
+    boundary_policy: BoundaryPolicy,
+    /// Bodies that were outside of the bounding box in the last set_all
+    escapers: Vec<usize>,
+}
+
+/// How detailed the mass distribution of an approximated node is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultipoleOrder {
+    /// Only the total mass at the center of mass
+    #[default]
+    Monopole,
+    /// Also takes the shape of the mass distribution into account.
+    /// More work per node, but allows for a larger theta at the same accuracy.
+    Quadrupole,
+}
+
+/// What to do with bodies that leave the bounding box.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BoundaryPolicy {
+    /// The bounding box never changes.
+    /// Bodies outside of it get the key of the closest point in the box, which makes their forces less accurate.
+    /// They are reported by [`CosmicSystem::escapers`].
+    #[default]
+    Fixed,
+    /// The bounding box grows and recenters, so that it always contains all bodies. It never shrinks.
+    Grow,
+    /// Every step, the bounding box becomes the smallest cube that contains all bodies.
+    /// No key resolution gets wasted on empty space.
+    Fit,
 }
 
 impl CosmicSystem {
+    /// The capacity is the number of bodies that is expected. The tree grows if there are more.
     pub fn new(bounding_box: BoundingBox, capacity: usize) -> Self {
         let capacity = capacity.next_power_of_two();
         let mut nodes = Vec::with_capacity(capacity);
@@ -31,16 +73,172 @@ impl CosmicSystem {
         Self {
             bounding_box,
             nodes,
+            reserved_capacity: capacity,
+            softening: Softening::None,
+            theta: 1.0,
+            inv_theta_squared: 1.0,
+            opening_criterion: OpeningCriterion::Geometric,
+            multipole_order: MultipoleOrder::Monopole,
+            boundary_policy: BoundaryPolicy::Fixed,
+            escapers: vec![],
+        }
+    }
+
+    pub fn bounding_box(&self) -> &BoundingBox {
+        &self.bounding_box
+    }
+
+    pub fn softening(&self) -> &Softening {
+        &self.softening
+    }
+
+    pub fn set_softening(&mut self, softening: Softening) {
+        self.softening = softening;
+    }
+
+    pub fn theta(&self) -> f64 {
+        self.theta
+    }
+
+    /// Smaller is more accurate, and 0 turns the tree into a direct summation.
+    /// Can be changed between steps.
+    pub fn set_theta(&mut self, theta: f64) {
+        assert!(theta >= 0.0, "theta: {}", theta);
+        self.theta = theta;
+        self.inv_theta_squared = 1.0 / (theta * theta);
+    }
+
+    pub fn opening_criterion(&self) -> &OpeningCriterion {
+        &self.opening_criterion
+    }
+
+    pub fn set_opening_criterion(&mut self, opening_criterion: OpeningCriterion) {
+        self.opening_criterion = opening_criterion;
+    }
+
+    pub fn multipole_order(&self) -> MultipoleOrder {
+        self.multipole_order
+    }
+
+    pub fn set_multipole_order(&mut self, multipole_order: MultipoleOrder) {
+        self.multipole_order = multipole_order;
+    }
+
+    pub fn boundary_policy(&self) -> BoundaryPolicy {
+        self.boundary_policy
+    }
+
+    pub fn set_boundary_policy(&mut self, boundary_policy: BoundaryPolicy) {
+        self.boundary_policy = boundary_policy;
+    }
+
+    /// The [`CelestialBody::index`] of every body that was outside of the bounding box in the last [`CosmicSystem::set_all`].
+    /// Always empty, unless the [`BoundaryPolicy`] is [`BoundaryPolicy::Fixed`].
+    pub fn escapers(&self) -> &[usize] {
+        &self.escapers
+    }
+
+    /// How many bodies fit into the tree without it having to grow.
+    pub fn capacity(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The capacity that the tree doesn't shrink below.
+    pub fn reserved_capacity(&self) -> usize {
+        self.reserved_capacity
+    }
+
+    /// Like [`CosmicSystem::reserve`], but can also lower the reserved capacity.
+    /// The tree only shrinks in the next [`CosmicSystem::set_all`] that has a lot fewer bodies.
+    pub fn set_reserved_capacity(&mut self, number_of_bodies: usize) {
+        self.reserved_capacity = number_of_bodies.next_power_of_two();
+        if self.nodes.len() < self.reserved_capacity {
+            self.resize(self.reserved_capacity);
+        }
+    }
+
+    /// Makes room for at least `number_of_bodies` bodies, and keeps it,
+    /// even if there are fewer bodies for a while.
+    pub fn reserve(&mut self, number_of_bodies: usize) {
+        self.reserved_capacity = self
+            .reserved_capacity
+            .max(number_of_bodies.next_power_of_two());
+        if self.nodes.len() < self.reserved_capacity {
+            self.resize(self.reserved_capacity);
+        }
+    }
+
+    /// Releases the memory that isn't needed for `number_of_bodies` bodies,
+    /// including the memory that got reserved.
+    pub fn shrink_to_fit(&mut self, number_of_bodies: usize) {
+        self.reserved_capacity = number_of_bodies.next_power_of_two();
+        self.resize(self.reserved_capacity);
+    }
+
+    /// The tree gets rebuilt in every set_all, so the old nodes don't have to be kept
+    fn resize(&mut self, capacity: usize) {
+        assert!(capacity.is_power_of_two());
+        self.nodes.resize(capacity, Default::default());
+        self.nodes.shrink_to_fit();
+    }
+
+    /// Grows the tree when there are too many bodies, and shrinks it when there are a lot fewer.
+    fn fit_capacity(&mut self, number_of_bodies: usize) {
+        let wanted = number_of_bodies.next_power_of_two();
+        if wanted > self.nodes.len() {
+            self.resize(wanted);
+        } else if wanted * 4 <= self.nodes.len() {
+            // Not right away, so that removing and adding a few bodies doesn't resize every step
+            self.resize((wanted * 2).max(self.reserved_capacity));
+        }
+    }
+
+    fn update_bounding_box(&mut self, bodies: &[CelestialBody]) {
+        match self.boundary_policy {
+            BoundaryPolicy::Fixed => {
+                let bounding_box = self.bounding_box;
+                self.escapers = bodies
+                    .par_iter()
+                    .filter(|body| !bounding_box.contains(body.position))
+                    .map(|body| body.index)
+                    .collect();
+            }
+            BoundaryPolicy::Grow => {
+                let extent = extent(bodies);
+                // Including the maximum, because the extent of bodies in a plane or of a single body is flat
+                let contained = bodies.is_empty() || self.bounding_box.contains_box(&extent);
+                if !contained {
+                    let union = self.bounding_box.union(&extent);
+                    // Some room to spare, so that it doesn't have to grow every step
+                    self.bounding_box =
+                        BoundingBox::from_center(union.center(), union.extent() * 0.5 * 1.25);
+                }
+                self.escapers.clear();
+            }
+            BoundaryPolicy::Fit => {
+                if !bodies.is_empty() {
+                    let extent = extent(bodies);
+                    let mut side_length = extent.side_length();
+                    if side_length <= 0.0 {
+                        // All bodies are in the same spot
+                        side_length = self.bounding_box.side_length();
+                    }
+                    self.bounding_box =
+                        BoundingBox::from_center(extent.center(), DVec3::splat(side_length * 0.5));
+                }
+                self.escapers.clear();
+            }
         }
     }
 
     pub fn set_all(&mut self, bodies: &mut Vec<CelestialBody>) {
+        self.update_bounding_box(bodies);
         bodies.par_iter_mut().for_each(|body| {
             body.key = z_order_curve(body.position, &self.bounding_box);
         });
         bodies.par_sort_by_key(|body| body.key);
 
-        assert!(bodies.len() <= self.nodes.len());
+        self.fit_capacity(bodies.len());
 
         // We basically start in the middle.
         // All the bottom - 1 layer nodes come here
@@ -48,7 +246,7 @@ impl CosmicSystem {
         let mut k = self.nodes.len() / 2;
         // We manually do the first iteration (bodies)
         let mut k_end = k + bodies.len() / 2;
-        (&mut self.nodes[k..k_end])
+        self.nodes[k..k_end]
             .par_iter_mut()
             .enumerate()
             .for_each(|(index, node)| {
@@ -59,6 +257,7 @@ impl CosmicSystem {
                 *node = CosmicSystemNode::from_bodies(
                     left_body,
                     right_body,
+                    left_body.key,
                     index_of_1,
                     &self.bounding_box,
                 );
@@ -70,8 +269,12 @@ impl CosmicSystem {
                 position: left_body.position,
                 mass: left_body.mass,
                 z_order: left_body.key,
+                first_key: left_body.key,
                 index_of_1: u8::MAX,
                 comparison_factor: -1.0,
+                cell_center: left_body.position,
+                quadrupole: DMat3::ZERO,
+                bounds: left_body.bounds(),
             };
             k_end += 1;
         }
@@ -111,20 +314,26 @@ impl CosmicSystem {
                 let right_node = &self.nodes[2 * node_index + 1];
 
                 self.nodes[node_index] = if left_node.mass > 0.0 && right_node.mass > 0.0 {
-                    let index_of_1 = index_of_1(left_node.z_order, right_node.z_order)
-                        .min(left_node.index_of_1.min(right_node.index_of_1));
+                    // The bodies are sorted, so this is the common prefix of all their keys
+                    let index_of_1 = index_of_1(left_node.first_key, right_node.first_key)
+                        .min(right_node.index_of_1);
 
-                    CosmicSystemNode::from_bodies(
+                    let mut node = CosmicSystemNode::from_bodies(
                         &left_node.body(),
                         &right_node.body(),
+                        left_node.first_key,
                         index_of_1,
                         &self.bounding_box,
-                    )
+                    );
+                    // from_bodies shifted the children to the new center of mass,
+                    // this adds the spread within the children
+                    node.quadrupole += left_node.quadrupole + right_node.quadrupole;
+                    node.bounds = left_node.bounds.union(&right_node.bounds);
+                    node
                 } else if left_node.mass > 0.0 {
                     // Only left node truly exists
-                    let mut node = std::mem::take(&mut self.nodes[2 * node_index]);
-                    node.comparison_factor = -1.0;
-                    node
+                    // It stays openable, walking into it visits the original and the empty right node
+                    left_node.clone()
                 } else {
                     assert!(right_node.mass <= 0.0);
                     // No nodes actually exist
@@ -141,38 +350,253 @@ impl CosmicSystem {
         body: &CelestialBody,
         bodies: &Vec<CelestialBody>,
     ) -> DVec3 {
-        fn helper(
-            k: usize,
-            body: &CelestialBody,
-            nodes: &Vec<CosmicSystemNode>,
-            bodies: &Vec<CelestialBody>,
-        ) -> DVec3 {
-            if k >= nodes.len() {
-                // We're querying a single body itself
-                let index = k - nodes.len();
-                return body.gravitational_force_zero_mass(&bodies[index]);
-            }
+        self.gravitational_force_zero_mass_relative(body, bodies, 0.0)
+    }
+
+    /// Takes the magnitude of the acceleration of the body in the previous step,
+    /// which [`OpeningCriterion::Relative`] needs. 0 if it isn't known.
+    pub fn gravitational_force_zero_mass_relative(
+        &self,
+        body: &CelestialBody,
+        bodies: &Vec<CelestialBody>,
+        previous_acceleration: f64,
+    ) -> DVec3 {
+        let mut force = DVec3::ZERO;
+        self.walk(
+            1,
+            body,
+            bodies,
+            previous_acceleration,
+            &mut |other, quadrupole| {
+                force += body.gravitational_force_zero_mass_softened(other, &self.softening);
+                if let Some(quadrupole) = quadrupole {
+                    force += quadrupole_force(quadrupole, body.position - other.position);
+                }
+            },
+        );
+        force * simulation::G
+    }
 
-            let node = &nodes[k];
+    /// Acceleration and gravitational potential (in J/kg) of the body,
+    /// with the same opening criterion and softening as the forces.
+    pub fn gravitational_force_and_potential_zero_mass(
+        &self,
+        body: &CelestialBody,
+        bodies: &Vec<CelestialBody>,
+        previous_acceleration: f64,
+    ) -> (DVec3, f64) {
+        let mut force = DVec3::ZERO;
+        let mut potential = 0.0;
+        self.walk(
+            1,
+            body,
+            bodies,
+            previous_acceleration,
+            &mut |other, quadrupole| {
+                force += body.gravitational_force_zero_mass_softened(other, &self.softening);
+                potential +=
+                    body.gravitational_potential_zero_mass_softened(other, &self.softening);
+                if let Some(quadrupole) = quadrupole {
+                    let delta = body.position - other.position;
+                    force += quadrupole_force(quadrupole, delta);
+                    potential += quadrupole_potential(quadrupole, delta);
+                }
+            },
+        );
+        (force * simulation::G, potential * simulation::G)
+    }
+
+    /// Calls `interact` with every body and every approximated node that acts on the body.
+    /// Nodes come with their quadrupole, if it should be used.
+    fn walk(
+        &self,
+        k: usize,
+        body: &CelestialBody,
+        bodies: &Vec<CelestialBody>,
+        previous_acceleration: f64,
+        interact: &mut impl FnMut(&CelestialBody, Option<&DMat3>),
+    ) {
+        if k >= self.nodes.len() {
+            // We're querying a single body itself
+            let index = k - self.nodes.len();
+            interact(&bodies[index], None);
+            return;
+        }
+
+        let node = &self.nodes[k];
+        if node.mass <= 0.0 {
+            // Empty right sibling of a node that only has a left child
+            return;
+        }
+
+        if self.can_approximate(node, body, previous_acceleration) {
             let node_body = node.body();
-            assert!(node.mass > 0.0);
+            if self.multipole_order == MultipoleOrder::Quadrupole && body.key != node_body.key {
+                interact(&node_body, Some(&node.quadrupole));
+            } else {
+                interact(&node_body, None);
+            }
+        } else {
+            assert!(node.comparison_factor >= 0.0);
+            // Always valid indices, because an openable node always has 2 children
+            // (A node of a single body has a comparison_factor of -1, so it always gets approximated before getting here)
+            // The right child is empty if this node is a copy of its only child, which is on the left
+            self.walk(2 * k, body, bodies, previous_acceleration, interact);
+            self.walk(2 * k + 1, body, bodies, previous_acceleration, interact);
+        }
+    }
 
-            if node.comparison_factor < 0.0 {
-                body.gravitational_force_zero_mass(&node_body)
-            } else if node.comparison_factor < body.distance_to_squared(&node_body) {
-                body.gravitational_force_zero_mass(&node_body)
+    /// Whether the node is far enough away from the body to be treated as a single body
+    #[inline]
+    fn can_approximate(
+        &self,
+        node: &CosmicSystemNode,
+        body: &CelestialBody,
+        previous_acceleration: f64,
+    ) -> bool {
+        if node.comparison_factor < 0.0 {
+            return true;
+        }
+        let distance_squared = body.position.distance_squared(node.position);
+        match self.opening_criterion {
+            OpeningCriterion::Relative { alpha } if previous_acceleration > 0.0 => {
+                // Like GADGET-2, bodies that are in or right next to the cell always open it
+                let width = node.comparison_factor.sqrt();
+                let inside = (body.position - node.cell_center).abs().max_element() < 0.6 * width;
+                !inside
+                    && simulation::G * node.mass * node.comparison_factor
+                        <= alpha * previous_acceleration * distance_squared * distance_squared
+            }
+            OpeningCriterion::CenterOfMassOffset => {
+                let offset = node.position.distance(node.cell_center);
+                let limit = node.comparison_factor.sqrt() / self.theta + offset;
+                limit * limit < distance_squared
+            }
+            _ => node.comparison_factor * self.inv_theta_squared < distance_squared,
+        }
+    }
+
+    /// Every pair of bodies whose spheres overlap, as positions in `bodies`, with the smaller position first.
+    /// Bodies without a radius never collide.
+    /// The bodies have to be the ones from the last [`CosmicSystem::set_all`], in the same order.
+    pub fn colliding_pairs(&self, bodies: &[CelestialBody]) -> Vec<(usize, usize)> {
+        bodies
+            .par_iter()
+            .enumerate()
+            .filter(|(_, body)| body.radius > 0.0)
+            .flat_map_iter(|(i, body)| {
+                let mut pairs = vec![];
+                self.visit_intersecting(1, &body.bounds(), bodies, &mut |j| {
+                    let other = &bodies[j];
+                    let radii = body.radius + other.radius;
+                    if i < j
+                        && other.radius > 0.0
+                        && body.position.distance_squared(other.position) < radii * radii
+                    {
+                        pairs.push((i, j));
+                    }
+                });
+                pairs
+            })
+            .collect()
+    }
+
+    /// [`CelestialBody::index`] of all bodies whose center is within `radius` of `center`, in no particular order.
+    /// The bodies have to be the ones from the last [`CosmicSystem::set_all`], in the same order.
+    pub fn bodies_within(
+        &self,
+        center: DVec3,
+        radius: f64,
+        bodies: &[CelestialBody],
+    ) -> Vec<usize> {
+        let query = BoundingBox::from_center(center, DVec3::splat(radius));
+        let mut found = vec![];
+        self.visit_intersecting(1, &query, bodies, &mut |index| {
+            if bodies[index].position.distance_squared(center) <= radius * radius {
+                found.push(bodies[index].index);
+            }
+        });
+        found
+    }
+
+    /// [`CelestialBody::index`] of the `k` bodies that are closest to the point, the closest first.
+    /// The bodies have to be the ones from the last [`CosmicSystem::set_all`], in the same order.
+    pub fn k_nearest(&self, point: DVec3, k: usize, bodies: &[CelestialBody]) -> Vec<usize> {
+        // Squared distances are never negative, so their bits sort the same way as the floats
+        let mut nearest: BinaryHeap<(u64, usize)> = BinaryHeap::with_capacity(k + 1);
+        let mut queue: BinaryHeap<Reverse<(u64, usize)>> = BinaryHeap::new();
+        let push = |queue: &mut BinaryHeap<Reverse<(u64, usize)>>, node_index: usize| {
+            if node_index >= self.nodes.len() {
+                let index = node_index - self.nodes.len();
+                if index < bodies.len() {
+                    let distance_squared = bodies[index].position.distance_squared(point);
+                    queue.push(Reverse((distance_squared.to_bits(), node_index)));
+                }
+            } else if self.nodes[node_index].mass > 0.0 {
+                let distance_squared = self.nodes[node_index].bounds.distance_squared(point);
+                queue.push(Reverse((distance_squared.to_bits(), node_index)));
+            }
+        };
+
+        // Best first, the closest node or body is always the next one
+        if k > 0 {
+            push(&mut queue, 1);
+        }
+        while let Some(Reverse((distance_squared, node_index))) = queue.pop() {
+            if nearest.len() == k && distance_squared >= nearest.peek().unwrap().0 {
+                break;
+            }
+            if node_index >= self.nodes.len() {
+                nearest.push((distance_squared, node_index - self.nodes.len()));
+                if nearest.len() > k {
+                    nearest.pop();
+                }
             } else {
-                assert!(node.comparison_factor >= 0.0);
-                // Always valid indices, because a node always has 2 children
-                // (If it only had one body as its child, then it would have a comparison_factor to -1, causing the function to return before getting here)
-                helper(2 * k, body, nodes, bodies) + helper(2 * k + 1, body, nodes, bodies)
+                push(&mut queue, 2 * node_index);
+                push(&mut queue, 2 * node_index + 1);
             }
         }
+        nearest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|(_, index)| bodies[index].index)
+            .collect()
+    }
 
-        helper(1, body, &self.nodes, bodies) * simulation::G
+    /// Calls `visit` with the position in `bodies` of every body whose bounds intersect the query box
+    fn visit_intersecting(
+        &self,
+        k: usize,
+        query: &BoundingBox,
+        bodies: &[CelestialBody],
+        visit: &mut impl FnMut(usize),
+    ) {
+        if k >= self.nodes.len() {
+            let index = k - self.nodes.len();
+            // The right body of a lone left body doesn't exist
+            if index < bodies.len() && bodies[index].bounds().intersects(query) {
+                visit(index);
+            }
+            return;
+        }
+
+        let node = &self.nodes[k];
+        if node.mass <= 0.0 || !node.bounds.intersects(query) {
+            return;
+        }
+        self.visit_intersecting(2 * k, query, bodies, visit);
+        self.visit_intersecting(2 * k + 1, query, bodies, visit);
     }
 }
 
+/// Smallest box that contains all bodies
+fn extent(bodies: &[CelestialBody]) -> BoundingBox {
+    bodies
+        .par_iter()
+        .map(|body| BoundingBox::new(body.position, body.position))
+        .reduce(|| BoundingBox::EMPTY, |a, b| a.union(&b))
+}
+
 /// Index of the bit where the z-orders differ
 fn index_of_1(a: u128, b: u128) -> u8 {
     let index_of_1 = (a ^ b).leading_zeros() as u8;
@@ -183,10 +607,53 @@ fn index_of_1(a: u128, b: u128) -> u8 {
     }
 }
 
-/// Side length for barnes hut
-fn side_length(number_of_splits: u8, bounding_box: &BoundingBox) -> f64 {
+/// Side lengths along every axis of a cell for barnes hut.
+/// The cells have the same proportions as the bounding box.
+fn cell_extent(number_of_splits: u8, bounding_box: &BoundingBox) -> DVec3 {
     let number_of_cube_splits = number_of_splits / 3;
-    bounding_box.side_length() / ((1u128 << number_of_cube_splits) as f64)
+    bounding_box.extent() / ((1u128 << number_of_cube_splits) as f64)
+}
+
+/// Longest side length of a cell for barnes hut
+fn side_length(number_of_splits: u8, bounding_box: &BoundingBox) -> f64 {
+    cell_extent(number_of_splits, bounding_box).max_element()
+}
+
+/// Traceless quadrupole tensor `mass * (3 * offset * offset^T - |offset|^2 * I)`
+/// of a point mass at an offset from the center of mass
+fn point_quadrupole(mass: f64, offset: DVec3) -> DMat3 {
+    let outer_product = DMat3::from_cols(offset * offset.x, offset * offset.y, offset * offset.z);
+    (outer_product * 3.0 - DMat3::IDENTITY * offset.length_squared()) * mass
+}
+
+/// Acceleration from the quadrupole term of the potential `-1/2 * delta^T * Q * delta / |delta|^5`,
+/// where delta points from the center of mass to the body. Without G.
+#[inline]
+fn quadrupole_force(quadrupole: &DMat3, delta: DVec3) -> DVec3 {
+    let distance_squared = delta.length_squared();
+    let inv_distance_5 = 1.0 / (distance_squared * distance_squared * distance_squared.sqrt());
+    let q_delta = *quadrupole * delta;
+    let projection = delta.dot(q_delta);
+    (q_delta - delta * (2.5 * projection / distance_squared)) * inv_distance_5
+}
+
+/// The quadrupole term of the potential, without G
+#[inline]
+fn quadrupole_potential(quadrupole: &DMat3, delta: DVec3) -> f64 {
+    let distance_squared = delta.length_squared();
+    let inv_distance_5 = 1.0 / (distance_squared * distance_squared * distance_squared.sqrt());
+    -0.5 * delta.dot(*quadrupole * delta) * inv_distance_5
+}
+
+/// Center of the cell that all bodies with the same first `number_of_splits` bits of the key are in
+fn cell_center(key: u128, number_of_splits: u8, bounding_box: &BoundingBox) -> DVec3 {
+    let number_of_cube_splits = (number_of_splits / 3) as u32;
+    let cell_extent = cell_extent(number_of_splits, bounding_box);
+    let cell = z_order_curve_inverse(key).map(|coordinate| {
+        // Only keep the bits that are the same for all bodies in the cell
+        (coordinate as u64 >> (32 - number_of_cube_splits)) as f64
+    });
+    bounding_box.min + (DVec3::from_array(cell) + 0.5) * cell_extent
 }
 
 /// Comparison factor for barnes hut
@@ -201,7 +668,7 @@ fn comparison_factor(number_of_splits: u8, bounding_box: &BoundingBox) -> f64 {
         number_of_splits
     );
     let side_length = side_length(number_of_splits, bounding_box);
-    side_length * side_length * INV_T_SQUARED
+    side_length * side_length
 }
 
 /// A node always has 2 children
@@ -218,9 +685,18 @@ struct CosmicSystemNode {
     /// width^2 / distance^2 < T^2 = Optimisation
     /// width^2 < T^2 * distance^2 = Optimisation
     /// width^2 * (1/T^2) < distance^2 = Optimisation
+    /// Only stores width^2, so that T can be changed without rebuilding the tree.
     comparison_factor: f64,
+    /// Center of the cube that the node covers
+    cell_center: DVec3,
+    /// Traceless quadrupole tensor around the center of mass
+    quadrupole: DMat3,
+    /// Contains the spheres of all bodies in the node
+    bounds: BoundingBox,
 
     z_order: u128,
+    /// Key of the first body in the node
+    first_key: u128,
     index_of_1: u8,
 }
 
@@ -231,8 +707,10 @@ impl CosmicSystemNode {
     pub fn body(&self) -> CelestialBody {
         CelestialBody {
             index: 0,
+            id: 0,
             position: self.position,
             mass: self.mass,
+            radius: 0.0,
             key: self.z_order,
         }
     }
@@ -240,6 +718,7 @@ impl CosmicSystemNode {
     pub fn from_bodies(
         a: &CelestialBody,
         b: &CelestialBody,
+        first_key: u128,
         index_of_1: u8,
         bounding_box: &BoundingBox,
     ) -> Self {
@@ -255,18 +734,30 @@ impl CosmicSystemNode {
         };
         let merged = CelestialBody {
             index: 0,
+            id: 0,
             mass,
+            radius: 0.0,
             position,
             key,
         };
 
         // If nodes have the same key, then index_of_1 is u8::MAX, which the comparison_factor function handles
+        let cell_center = if index_of_1 == u8::MAX {
+            merged.position
+        } else {
+            cell_center(first_key, index_of_1, bounding_box)
+        };
         CosmicSystemNode {
             position: merged.position,
             mass: merged.mass,
             z_order: merged.key,
+            first_key,
             index_of_1,
-            comparison_factor: comparison_factor(index_of_1, &bounding_box),
+            comparison_factor: comparison_factor(index_of_1, bounding_box),
+            cell_center,
+            quadrupole: point_quadrupole(a.mass, a.position - position)
+                + point_quadrupole(b.mass, b.position - position),
+            bounds: a.bounds().union(&b.bounds()),
         }
     }
 }
@@ -278,15 +769,19 @@ impl Default for CosmicSystemNode {
             position: DVec3::ZERO,
             mass: 0.0,
             z_order: 0,
+            first_key: 0,
             index_of_1: u8::MAX,
             comparison_factor: -1.0,
+            cell_center: DVec3::ZERO,
+            quadrupole: DMat3::ZERO,
+            bounds: BoundingBox::EMPTY,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::simulation;
+    use crate::{direct::direct_sum_accelerations, simulation};
 
     use super::*;
 
@@ -311,25 +806,315 @@ mod tests {
             bounding_box.side_length() / 2.0
         );
     }
-    /*
+
     #[test]
     fn test_with_equally_spaced_bodies() {
         let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
         let mut bodies = vec![
-            CelestialBody::new(1.0, DVec3::new(10.0, 0.0, 0.0)),
-            // CelestialBody::new(1.0, DVec3::new(0.0, 0.0, 0.0)),
-            CelestialBody::new(2.0, DVec3::new(-10.0, 0.0, 0.0)),
-            // CelestialBody::new(1.0, DVec3::new(20.0, 0.0, 0.0)),
-            // CelestialBody::new(1.0, DVec3::new(30.0, 0.0, 0.0)),
+            CelestialBody::new(0, 1.0, DVec3::new(10.0, 0.0, 0.0)),
+            CelestialBody::new(1, 2.0, DVec3::new(-10.0, 0.0, 0.0)),
+            CelestialBody::new(2, 1.0, DVec3::new(20.0, 0.0, 0.0)),
         ];
 
         let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
         cosmic_system.set_all(&mut bodies);
-        println!("{:#?}", bodies);
 
-        println!("{:#?}", cosmic_system.nodes);
+        let body = bodies.iter().find(|body| body.index == 0).unwrap();
+        let force = cosmic_system.gravitational_force_zero_mass(body, &bodies);
+        // 2 kg at a distance of 20 m to the left, 1 kg at a distance of 10 m to the right
+        let expected = (-2.0 / 400.0 + 1.0 / 100.0) * simulation::G;
+        assert!((force.x - expected).abs() < 1e-9 * expected, "{}", force);
+        assert_eq!(force.y, 0.0);
+        assert_eq!(force.z, 0.0);
+    }
 
-        let force = cosmic_system.gravitational_force_zero_mass(&bodies[0], &bodies);
-        println!("{:#?}", force);
-    } */
+    /// First and last key of the bodies below a node, `None` if it is empty
+    fn key_range(
+        cosmic_system: &CosmicSystem,
+        k: usize,
+        bodies: &[CelestialBody],
+    ) -> Option<(u128, u128)> {
+        if k >= cosmic_system.nodes.len() {
+            return bodies
+                .get(k - cosmic_system.nodes.len())
+                .map(|body| (body.key, body.key));
+        }
+        let left = key_range(cosmic_system, 2 * k, bodies)?;
+        match key_range(cosmic_system, 2 * k + 1, bodies) {
+            Some(right) => Some((left.0, right.1)),
+            None => Some(left),
+        }
+    }
+
+    #[test]
+    fn test_node_cells() {
+        let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
+        // Two tight clusters, so that the centers of mass are far from the cells of the nodes
+        let mut bodies: Vec<_> = (0..301)
+            .map(|i| {
+                let t = i as f64;
+                let center = if i % 2 == 0 { 60.0 } else { -35.0 };
+                let offset = DVec3::new((t * 0.37).sin(), (t * 0.71).cos(), (t * 0.13).sin());
+                CelestialBody::new(i, 1.0 + t, DVec3::splat(center) + offset * 5.0)
+            })
+            .collect();
+        let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+        cosmic_system.set_all(&mut bodies);
+
+        for k in 1..cosmic_system.nodes.len() {
+            let node = &cosmic_system.nodes[k];
+            let Some((first, last)) = key_range(&cosmic_system, k, &bodies) else {
+                assert_eq!(node.mass, 0.0);
+                continue;
+            };
+            assert_eq!(node.first_key, first);
+            if node.comparison_factor < 0.0 {
+                continue;
+            }
+            // The common prefix of all keys, so the cell contains every body of the node
+            assert_eq!(node.index_of_1, index_of_1(first, last), "node {}", k);
+            let half_extent = cell_extent(node.index_of_1, &bounding_box) * 0.5;
+            let cell = BoundingBox::from_center(node.cell_center, half_extent * (1.0 + 1e-9));
+            assert!(
+                node.bounds.intersection(&cell) == Some(node.bounds),
+                "node {}",
+                k
+            );
+        }
+    }
+
+    #[test]
+    fn test_lone_left_child_stays_openable() {
+        let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
+        // 6 bodies in 8 leaves: the right half of the tree only has the pair of bodies 4 and 5
+        let mut bodies: Vec<_> = (0..6)
+            .map(|i| CelestialBody::new(i, 1.0, DVec3::new(-90.0 + 30.0 * i as f64, 1.0, 2.0)))
+            .collect();
+        let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+        cosmic_system.set_theta(0.0);
+        cosmic_system.set_all(&mut bodies);
+        assert_eq!(cosmic_system.nodes.len(), 8);
+        assert!(cosmic_system.nodes[3].comparison_factor >= 0.0);
+        assert_eq!(cosmic_system.nodes[7].mass, 0.0);
+
+        // With theta 0 everything gets opened, including the copy of the pair
+        let reference = direct_sum_accelerations(&bodies, cosmic_system.softening());
+        for (body, reference) in bodies.iter().zip(reference) {
+            let force = cosmic_system.gravitational_force_zero_mass(body, &bodies);
+            assert!(
+                force.distance(reference) <= 1e-12 * reference.length(),
+                "{} {}",
+                force,
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn test_quadrupole_beats_monopole() {
+        let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
+        // Elongated clumps, whose mass distribution is far from a point
+        let mut bodies: Vec<_> = (0..400)
+            .map(|i| {
+                let t = i as f64;
+                let center = DVec3::new(-60.0 + 40.0 * (i % 4) as f64, 20.0 * (i % 3) as f64, 0.0);
+                let offset = DVec3::new(8.0 * (t * 0.37).sin(), (t * 0.71).cos(), (t * 0.13).sin());
+                CelestialBody::new(i, 1.0 + (i % 5) as f64, center + offset)
+            })
+            .collect();
+        let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+        cosmic_system.set_theta(0.7);
+        cosmic_system.set_all(&mut bodies);
+        let reference = direct_sum_accelerations(&bodies, cosmic_system.softening());
+
+        let mut mean_error = |multipole_order| {
+            cosmic_system.set_multipole_order(multipole_order);
+            bodies
+                .iter()
+                .zip(&reference)
+                .map(|(body, reference)| {
+                    let force = cosmic_system.gravitational_force_zero_mass(body, &bodies);
+                    force.distance(*reference) / reference.length()
+                })
+                .sum::<f64>()
+                / bodies.len() as f64
+        };
+        let monopole = mean_error(MultipoleOrder::Monopole);
+        let quadrupole = mean_error(MultipoleOrder::Quadrupole);
+        assert!(quadrupole < 0.5 * monopole, "{} {}", quadrupole, monopole);
+    }
+
+    #[test]
+    fn test_neighbour_queries() {
+        let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
+        let mut bodies: Vec<_> = (0..300)
+            .map(|i| {
+                let t = i as f64;
+                let position =
+                    DVec3::new((t * 0.37).sin(), (t * 0.71).cos(), (t * 0.13).sin()) * 90.0;
+                CelestialBody::new(i, 1.0, position)
+            })
+            .collect();
+        let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+        cosmic_system.set_all(&mut bodies);
+
+        // Sorting shuffled the bodies, so positions in the slice and indices differ
+        assert!(bodies.iter().enumerate().any(|(i, body)| body.index != i));
+
+        let point = DVec3::new(10.0, -20.0, 5.0);
+        let mut by_distance = bodies.clone();
+        by_distance.sort_by(|a, b| {
+            let distance = |body: &CelestialBody| body.position.distance_squared(point);
+            distance(a).total_cmp(&distance(b))
+        });
+        let by_distance: Vec<usize> = by_distance.iter().map(|body| body.index).collect();
+
+        assert_eq!(
+            cosmic_system.k_nearest(point, 10, &bodies),
+            by_distance[..10]
+        );
+        assert_eq!(cosmic_system.k_nearest(point, 1000, &bodies), by_distance);
+        assert!(cosmic_system.k_nearest(point, 0, &bodies).is_empty());
+
+        let radius = 50.0;
+        let mut within = cosmic_system.bodies_within(point, radius, &bodies);
+        within.sort();
+        let mut expected: Vec<usize> = bodies
+            .iter()
+            .filter(|body| body.position.distance(point) <= radius)
+            .map(|body| body.index)
+            .collect();
+        expected.sort();
+        assert!(!expected.is_empty());
+        assert_eq!(within, expected);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
+        let mut bodies: Vec<_> = (0..5)
+            .map(|i| CelestialBody::new(i, 1.0, DVec3::new(10.0 * i as f64, 0.0, 0.0)))
+            .collect();
+
+        let mut cosmic_system = CosmicSystem::new(bounding_box, 2);
+        cosmic_system.set_all(&mut bodies);
+        assert_eq!(cosmic_system.capacity(), 8);
+        let body = bodies.iter().find(|body| body.index == 0).unwrap();
+        let force = cosmic_system.gravitational_force_zero_mass(body, &bodies);
+        let expected = (1.0 + 1.0 / 4.0 + 1.0 / 9.0 + 1.0 / 16.0) / 100.0 * simulation::G;
+        assert!((force.x - expected).abs() < 1e-9 * expected, "{}", force);
+
+        // Doesn't shrink below the capacity it was created with
+        bodies.truncate(1);
+        cosmic_system.set_all(&mut bodies);
+        assert_eq!(cosmic_system.capacity(), 2);
+
+        cosmic_system.reserve(100);
+        assert_eq!(cosmic_system.capacity(), 128);
+        cosmic_system.set_all(&mut bodies);
+        assert_eq!(cosmic_system.capacity(), 128);
+        cosmic_system.shrink_to_fit(bodies.len());
+        assert_eq!(cosmic_system.capacity(), 1);
+    }
+
+    #[test]
+    fn test_outside_on_one_axis() {
+        let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
+        let positions = [
+            DVec3::ZERO,
+            DVec3::new(0.0, -150.0, 0.0),
+            DVec3::new(150.0, 0.0, 0.0),
+            DVec3::new(10.0, 20.0, 30.0),
+        ];
+        let new_bodies = || -> Vec<_> {
+            positions
+                .iter()
+                .enumerate()
+                .map(|(i, &position)| CelestialBody::new(i, 1.0, position))
+                .collect()
+        };
+
+        let mut bodies = new_bodies();
+        let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+        cosmic_system.set_all(&mut bodies);
+        let mut escapers = cosmic_system.escapers().to_vec();
+        escapers.sort();
+        assert_eq!(escapers, [1, 2]);
+
+        let mut bodies = new_bodies();
+        let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+        cosmic_system.set_boundary_policy(BoundaryPolicy::Grow);
+        cosmic_system.set_all(&mut bodies);
+        assert!(cosmic_system.escapers().is_empty());
+        for position in positions {
+            assert!(
+                cosmic_system.bounding_box().contains(position),
+                "{}",
+                position
+            );
+        }
+    }
+
+    #[test]
+    fn test_fit() {
+        let bounding_box = BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0);
+        let mut cosmic_system = CosmicSystem::new(bounding_box, 4);
+        cosmic_system.set_boundary_policy(BoundaryPolicy::Fit);
+
+        // In a plane, and partly outside of the old box
+        let mut bodies: Vec<_> = [
+            DVec3::new(-10.0, 0.0, 5.0),
+            DVec3::new(30.0, 20.0, 5.0),
+            DVec3::new(150.0, 8.0, 5.0),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, position)| CelestialBody::new(i, 1.0, position))
+        .collect();
+        cosmic_system.set_all(&mut bodies);
+        assert_eq!(
+            *cosmic_system.bounding_box(),
+            BoundingBox::from_center(DVec3::new(70.0, 10.0, 5.0), DVec3::splat(80.0))
+        );
+        assert!(cosmic_system.escapers().is_empty());
+
+        // All in the same spot, which keeps the side length
+        for body in &mut bodies {
+            body.position = DVec3::new(1.0, 2.0, 3.0);
+        }
+        cosmic_system.set_all(&mut bodies);
+        assert_eq!(
+            *cosmic_system.bounding_box(),
+            BoundingBox::from_center(DVec3::new(1.0, 2.0, 3.0), DVec3::splat(80.0))
+        );
+        let body = bodies[0];
+        let force = cosmic_system.gravitational_force_zero_mass(&body, &bodies);
+        assert!(force.is_finite(), "{}", force);
+    }
+
+    #[test]
+    fn test_grow_with_flat_extent() {
+        let disk: Vec<_> = (0..100)
+            .map(|i| {
+                let t = i as f64;
+                let position = DVec3::new((t * 2.4).cos(), (t * 2.4).sin(), 0.0) * t;
+                CelestialBody::new(i, 1.0, position)
+            })
+            .collect();
+        let single = vec![CelestialBody::new(0, 1.0, DVec3::new(5.0, -3.0, 2.0))];
+        // Flat boxes, like the ones that GADGET files without a box size get
+        let flat = BoundingBox::new(DVec3::new(-1.0, -1.0, 0.0), DVec3::new(1.0, 1.0, 0.0));
+        let point = BoundingBox::new(DVec3::ZERO, DVec3::ZERO);
+        for (mut bodies, bounding_box) in [(disk, flat), (single.clone(), point), (single, flat)] {
+            let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+            cosmic_system.set_boundary_policy(BoundaryPolicy::Grow);
+            cosmic_system.set_all(&mut bodies);
+            let grown = *cosmic_system.bounding_box();
+            assert_ne!(grown, bounding_box);
+            for _ in 0..10 {
+                cosmic_system.set_all(&mut bodies);
+                assert_eq!(*cosmic_system.bounding_box(), grown);
+            }
+        }
+    }
 }
@@ -0,0 +1,337 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use glam::DVec3;
+use rayon::prelude::*;
+
+use crate::{celestial_body::CelestialBody, cosmic_system::CosmicSystem, profiling::span};
+
+/// Quantities that tell whether a run is physically sane.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Diagnostics {
+    pub total_mass: f64,
+    pub kinetic_energy: f64,
+    /// Computed with the tree, so it has the same approximations and softening as the forces.
+    pub potential_energy: f64,
+    pub momentum: DVec3,
+    /// Around the origin
+    pub angular_momentum: DVec3,
+    pub center_of_mass: DVec3,
+    /// `2 * kinetic / |potential|`, which is 1 for a system in virial equilibrium.
+    /// `None` without potential energy, like for a single body.
+    pub virial_ratio: Option<f64>,
+}
+
+impl Diagnostics {
+    /// `velocities` are indexed by [`CelestialBody::index`], like [`crate::simulation::UpdateBodies::movements`].
+    /// `accelerations` are the ones from the last step, like [`crate::simulation::UpdateBodies::forces`],
+    /// which the [`crate::opening_criterion::OpeningCriterion::Relative`] criterion needs.
+    /// Without them, like before the first step, it falls back to the geometric criterion, just like the first step does.
+    /// Rebuilds the tree, so that the potential matches the current positions.
+    /// Like every [`CosmicSystem::set_all`], that sorts `bodies` along the Z-order curve,
+    /// so positions in `bodies` from before aren't valid anymore. The [`CelestialBody::index`] stays the same.
+    pub fn compute(
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &[DVec3],
+        accelerations: &[DVec3],
+        cosmic_system: &mut CosmicSystem,
+    ) -> Self {
+        let _span = span!("Diagnostics");
+        cosmic_system.set_all(bodies);
+        let cosmic_system = &*cosmic_system;
+        let bodies = &*bodies;
+
+        let has_accelerations = accelerations.len() == bodies.len();
+        let potential_energy = 0.5
+            * bodies
+                .par_iter()
+                .map(|body| {
+                    let previous_acceleration = if has_accelerations {
+                        accelerations[body.index].length()
+                    } else {
+                        0.0
+                    };
+                    let (_, potential) = cosmic_system.gravitational_force_and_potential_zero_mass(
+                        body,
+                        bodies,
+                        previous_acceleration,
+                    );
+                    body.mass * potential
+                })
+                .sum::<f64>();
+
+        let mut total_mass = 0.0;
+        let mut kinetic_energy = 0.0;
+        let mut momentum = DVec3::ZERO;
+        let mut angular_momentum = DVec3::ZERO;
+        let mut weighted_position = DVec3::ZERO;
+        for body in bodies {
+            let velocity = velocities[body.index];
+            total_mass += body.mass;
+            kinetic_energy += 0.5 * body.mass * velocity.length_squared();
+            momentum += velocity * body.mass;
+            angular_momentum += body.position.cross(velocity) * body.mass;
+            weighted_position += body.position * body.mass;
+        }
+
+        Self {
+            total_mass,
+            kinetic_energy,
+            potential_energy,
+            momentum,
+            angular_momentum,
+            center_of_mass: if total_mass > 0.0 {
+                weighted_position / total_mass
+            } else {
+                DVec3::ZERO
+            },
+            virial_ratio: (potential_energy != 0.0)
+                .then(|| 2.0 * kinetic_energy / potential_energy.abs()),
+        }
+    }
+
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy
+    }
+}
+
+/// How far the diagnostics moved away from the ones at the start.
+/// Relative to the starting values, or absolute if they started out at zero.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Drift {
+    pub step: u64,
+    pub energy: f64,
+    pub momentum: f64,
+    pub angular_momentum: f64,
+    /// In m
+    pub center_of_mass: f64,
+    pub virial_ratio: Option<f64>,
+}
+
+impl Drift {
+    pub fn new(step: u64, initial: &Diagnostics, current: &Diagnostics) -> Self {
+        fn relative(initial: f64, change: f64) -> f64 {
+            if initial == 0.0 {
+                change
+            } else {
+                change / initial
+            }
+        }
+
+        Self {
+            step,
+            energy: relative(
+                initial.total_energy().abs(),
+                current.total_energy() - initial.total_energy(),
+            ),
+            momentum: relative(
+                initial.momentum.length(),
+                current.momentum.distance(initial.momentum),
+            ),
+            angular_momentum: relative(
+                initial.angular_momentum.length(),
+                current.angular_momentum.distance(initial.angular_momentum),
+            ),
+            center_of_mass: current.center_of_mass.distance(initial.center_of_mass),
+            virial_ratio: current.virial_ratio,
+        }
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {}: energy {:+.3e}, momentum {:.3e}, angular momentum {:.3e}, center of mass moved {:.3e} m",
+            self.step,
+            self.energy,
+            self.momentum,
+            self.angular_momentum,
+            self.center_of_mass,
+        )?;
+        match self.virial_ratio {
+            Some(virial_ratio) => write!(f, ", virial ratio {:.4}", virial_ratio),
+            None => write!(f, ", no virial ratio"),
+        }
+    }
+}
+
+/// Remembers the diagnostics from the first observed step,
+/// and reports the drift away from them every few steps.
+#[derive(Clone, Debug)]
+pub struct DriftMonitor {
+    pub every: u64,
+    pub initial: Option<Diagnostics>,
+}
+
+impl DriftMonitor {
+    pub fn new(every: u64) -> Self {
+        assert!(every > 0);
+        Self {
+            every,
+            initial: None,
+        }
+    }
+
+    /// Only calls `compute` on the steps where something gets reported.
+    pub fn observe(
+        &mut self,
+        step: u64,
+        compute: impl FnOnce() -> Diagnostics,
+    ) -> Option<(Diagnostics, Drift)> {
+        if self.initial.is_some() && !step.is_multiple_of(self.every) {
+            return None;
+        }
+        let current = compute();
+        let initial = *self.initial.get_or_insert(current);
+        Some((current, Drift::new(step, &initial, &current)))
+    }
+
+    /// Writes a line with the drift every few steps, for example to `std::io::stdout()`.
+    pub fn log(
+        &mut self,
+        step: u64,
+        compute: impl FnOnce() -> Diagnostics,
+        writer: &mut impl Write,
+    ) -> io::Result<()> {
+        match self.observe(step, compute) {
+            Some((_, drift)) => writeln!(writer, "{}", drift),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bounding_box::BoundingBox, direct::direct_sum_accelerations,
+        opening_criterion::OpeningCriterion, simulation, softening::Softening,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_two_bodies() {
+        let mut bodies = vec![
+            CelestialBody::new(0, 1e3, DVec3::new(-1.0, 0.0, 0.0)),
+            CelestialBody::new(1, 2e3, DVec3::new(2.0, 0.0, 0.0)),
+        ];
+        let velocities = [DVec3::new(0.0, 2.0, 0.0), DVec3::new(0.0, -1.0, 0.0)];
+        let mut cosmic_system =
+            CosmicSystem::new(BoundingBox::new(DVec3::ONE * -10.0, DVec3::ONE * 10.0), 2);
+        cosmic_system.set_theta(0.0);
+
+        let diagnostics = Diagnostics::compute(&mut bodies, &velocities, &[], &mut cosmic_system);
+        let potential_energy = -simulation::G * 1e3 * 2e3 / 3.0;
+        assert_eq!(diagnostics.total_mass, 3e3);
+        assert_eq!(
+            diagnostics.kinetic_energy,
+            0.5 * 1e3 * 4.0 + 0.5 * 2e3 * 1.0
+        );
+        assert!((diagnostics.potential_energy / potential_energy - 1.0).abs() < 1e-12);
+        assert_eq!(diagnostics.momentum, DVec3::ZERO);
+        assert_eq!(diagnostics.angular_momentum, DVec3::new(0.0, 0.0, -6e3));
+        assert_eq!(diagnostics.center_of_mass, DVec3::new(1.0, 0.0, 0.0));
+        let virial_ratio = 2.0 * 3e3 / potential_energy.abs();
+        assert!((diagnostics.virial_ratio.unwrap() / virial_ratio - 1.0).abs() < 1e-12);
+
+        // Without anything to attract, there is no virial ratio
+        bodies.truncate(1);
+        let diagnostics = Diagnostics::compute(&mut bodies, &velocities, &[], &mut cosmic_system);
+        assert_eq!(diagnostics.potential_energy, 0.0);
+        assert_eq!(diagnostics.virial_ratio, None);
+        let diagnostics = Diagnostics::compute(&mut vec![], &[], &[], &mut cosmic_system);
+        assert_eq!(diagnostics.center_of_mass, DVec3::ZERO);
+        assert_eq!(diagnostics.virial_ratio, None);
+    }
+
+    #[test]
+    fn test_relative_opening_criterion() {
+        let mut bodies: Vec<CelestialBody> = (0..500)
+            .map(|i| {
+                let t = i as f64;
+                let position = DVec3::new((t * 0.37).sin(), (t * 0.71).cos(), (t * 0.13).sin())
+                    * (50.0 + 40.0 * (t * 0.05).sin());
+                CelestialBody::new(i, 1.0 + (i % 7) as f64, position)
+            })
+            .collect();
+        let velocities = vec![DVec3::ZERO; bodies.len()];
+        let mut cosmic_system = CosmicSystem::new(
+            BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0),
+            bodies.len(),
+        );
+        let potential_energy = |cosmic_system: &mut CosmicSystem,
+                                bodies: &mut Vec<CelestialBody>,
+                                accelerations: &[DVec3]| {
+            Diagnostics::compute(bodies, &velocities, accelerations, cosmic_system).potential_energy
+        };
+
+        cosmic_system.set_theta(0.0);
+        let exact = potential_energy(&mut cosmic_system, &mut bodies, &[]);
+        let mut accelerations = vec![DVec3::ZERO; bodies.len()];
+        for (body, acceleration) in bodies
+            .iter()
+            .zip(direct_sum_accelerations(&bodies, &Softening::None))
+        {
+            accelerations[body.index] = acceleration;
+        }
+
+        cosmic_system.set_theta(1.0);
+        let geometric = potential_energy(&mut cosmic_system, &mut bodies, &accelerations);
+        cosmic_system.set_opening_criterion(OpeningCriterion::Relative { alpha: 1e-4 });
+        let fallback = potential_energy(&mut cosmic_system, &mut bodies, &[]);
+        let relative = potential_energy(&mut cosmic_system, &mut bodies, &accelerations);
+        assert_eq!(fallback, geometric);
+        let error = |potential_energy: f64| (potential_energy / exact - 1.0).abs();
+        assert!(
+            error(relative) < error(geometric),
+            "{} {}",
+            relative,
+            geometric
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        let initial = Diagnostics {
+            total_mass: 1.0,
+            kinetic_energy: 2.0,
+            potential_energy: -6.0,
+            momentum: DVec3::new(3.0, 4.0, 0.0),
+            angular_momentum: DVec3::ZERO,
+            center_of_mass: DVec3::ZERO,
+            virial_ratio: Some(2.0 / 3.0),
+        };
+        let current = Diagnostics {
+            kinetic_energy: 2.5,
+            potential_energy: -6.3,
+            momentum: DVec3::new(3.0, 4.0, 1.0),
+            angular_momentum: DVec3::new(0.0, 0.5, 0.0),
+            center_of_mass: DVec3::new(0.0, 3.0, 4.0),
+            virial_ratio: Some(5.0 / 6.3),
+            ..initial
+        };
+
+        let drift = Drift::new(7, &initial, &current);
+        assert_eq!(drift.step, 7);
+        // From -4 to -3.8, relative to |-4|
+        assert!((drift.energy - 0.05).abs() < 1e-12, "{}", drift.energy);
+        // Moved by 1, relative to |(3, 4, 0)|
+        assert!((drift.momentum - 0.2).abs() < 1e-12);
+        // Started at zero, so it is absolute
+        assert_eq!(drift.angular_momentum, 0.5);
+        assert_eq!(drift.center_of_mass, 5.0);
+        assert_eq!(drift.virial_ratio, current.virial_ratio);
+
+        let mut monitor = DriftMonitor::new(10);
+        let mut output = vec![];
+        monitor.log(0, || initial, &mut output).unwrap();
+        monitor.log(5, || unreachable!(), &mut output).unwrap();
+        monitor.log(10, || current, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.starts_with("step 0: energy +0.000e0"), "{}", output);
+    }
+}
@@ -0,0 +1,135 @@
+use glam::DVec3;
+use rayon::prelude::*;
+
+use crate::{
+    celestial_body::CelestialBody, cosmic_system::CosmicSystem, profiling::span, simulation,
+    softening::Softening,
+};
+
+/// O(N^2) reference for the accelerations, in the same order as the bodies.
+/// Every pair of bodies interacts, except for bodies that sit on the exact same position.
+pub fn direct_sum_accelerations(bodies: &[CelestialBody], softening: &Softening) -> Vec<DVec3> {
+    let _span = span!("Direct summation");
+    bodies
+        .par_iter()
+        .map(|body| {
+            let mut force = DVec3::ZERO;
+            for other in bodies {
+                let delta = other.position - body.position;
+                let distance_squared = delta.length_squared();
+                if other.index != body.index && distance_squared > 0.0 {
+                    force += delta * (other.mass * softening.force_factor(distance_squared));
+                }
+            }
+            force * simulation::G
+        })
+        .collect()
+}
+
+/// Relative force errors of the tree, compared to the direct summation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccuracyReport {
+    pub mean: f64,
+    pub median: f64,
+    pub percentile_99: f64,
+    pub max: f64,
+}
+
+impl AccuracyReport {
+    /// Builds the tree and compares it with the direct summation, using the softening of the tree.
+    pub fn compute(cosmic_system: &mut CosmicSystem, bodies: &mut Vec<CelestialBody>) -> Self {
+        cosmic_system.set_all(bodies);
+        let cosmic_system = &*cosmic_system;
+        let bodies = &*bodies;
+
+        let reference = direct_sum_accelerations(bodies, cosmic_system.softening());
+        let errors = bodies
+            .par_iter()
+            .zip(reference.par_iter())
+            .map(|(body, reference)| {
+                let tree = cosmic_system.gravitational_force_zero_mass(body, bodies);
+                let length = reference.length();
+                if length > 0.0 {
+                    tree.distance(*reference) / length
+                } else {
+                    tree.length()
+                }
+            })
+            .collect();
+        Self::from_errors(errors)
+    }
+
+    pub fn from_errors(mut errors: Vec<f64>) -> Self {
+        assert!(!errors.is_empty());
+        errors.sort_by(f64::total_cmp);
+        // Nearest rank
+        let percentile = |p: f64| errors[((p * errors.len() as f64).ceil() as usize).max(1) - 1];
+        Self {
+            mean: errors.iter().sum::<f64>() / errors.len() as f64,
+            median: percentile(0.5),
+            percentile_99: percentile(0.99),
+            max: *errors.last().unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bounding_box::BoundingBox, cosmic_system::MultipoleOrder};
+
+    use super::*;
+
+    #[test]
+    fn test_tree_matches_direct_summation() {
+        // Deterministic, somewhat clumpy positions
+        let mut bodies: Vec<CelestialBody> = (0..1000)
+            .map(|i| {
+                let t = i as f64;
+                let position = DVec3::new((t * 0.37).sin(), (t * 0.71).cos(), (t * 0.13).sin())
+                    * (50.0 + 40.0 * (t * 0.05).sin());
+                CelestialBody::new(i, 1.0 + (i % 7) as f64, position)
+            })
+            .collect();
+        let mut cosmic_system = CosmicSystem::new(
+            BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0),
+            bodies.len(),
+        );
+
+        cosmic_system.set_theta(0.0);
+        let exact = AccuracyReport::compute(&mut cosmic_system, &mut bodies);
+        assert!(exact.max < 1e-12, "{:?}", exact);
+
+        cosmic_system.set_theta(0.5);
+        let monopole = AccuracyReport::compute(&mut cosmic_system, &mut bodies);
+        cosmic_system.set_multipole_order(MultipoleOrder::Quadrupole);
+        let quadrupole = AccuracyReport::compute(&mut cosmic_system, &mut bodies);
+        assert!(monopole.percentile_99 < 0.05, "{:?}", monopole);
+        assert!(quadrupole.median < monopole.median, "{:?}", quadrupole);
+    }
+
+    #[test]
+    fn test_flat_disk_in_rectangular_box() {
+        let mut bodies: Vec<CelestialBody> = (0..1000)
+            .map(|i| {
+                let t = i as f64;
+                let radius = 100.0 * (t / 1000.0).sqrt();
+                let position = DVec3::new(
+                    radius * (t * 2.4).cos(),
+                    radius * (t * 2.4).sin(),
+                    (t * 0.7).sin(),
+                );
+                CelestialBody::new(i, 1.0, position)
+            })
+            .collect();
+        let mut cosmic_system = CosmicSystem::new(
+            BoundingBox::new(
+                DVec3::new(-100.0, -100.0, -1.0),
+                DVec3::new(100.0, 100.0, 1.0),
+            ),
+            bodies.len(),
+        );
+        cosmic_system.set_theta(0.5);
+        let report = AccuracyReport::compute(&mut cosmic_system, &mut bodies);
+        assert!(report.percentile_99 < 0.05, "{:?}", report);
+    }
+}
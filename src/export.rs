@@ -0,0 +1,278 @@
+//! The bodies of a step as CSV or as a NumPy structured array, for looking at them in a notebook.
+//!
+//! Both have the columns `id`, `mass`, `x`, `y`, `z`, `vx`, `vy`, `vz`, `ax`, `ay`, `az` in SI units,
+//! one row per body ordered by id.
+//! Optionally followed by the Morton key, split into `key_high` and `key_low` because NumPy has no 128 bit integers.
+//! Sorting by both gives the order of the bodies in the tree.
+//!
+//! ```python
+//! bodies = numpy.load("bodies_000100.npy")
+//! bodies["x"][bodies["mass"] > 1e30]
+//! ```
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use glam::DVec3;
+
+use crate::{celestial_body::CelestialBody, simulation::UpdateBodies};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Npy,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Npy => "npy",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "csv" => Ok(ExportFormat::Csv),
+            "npy" => Ok(ExportFormat::Npy),
+            _ => Err(format!("Unknown export format {:?}", name)),
+        }
+    }
+}
+
+/// One row of the export
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExportRow {
+    pub id: u64,
+    pub mass: f64,
+    pub position: DVec3,
+    pub velocity: DVec3,
+    /// 0 if the integrator hasn't computed it yet
+    pub acceleration: DVec3,
+    pub key: u128,
+}
+
+impl ExportRow {
+    /// Ordered by id
+    pub fn collect(update_bodies: &UpdateBodies, bodies: &[CelestialBody]) -> Vec<Self> {
+        let has_accelerations = update_bodies.forces.len() == bodies.len();
+        let mut rows: Vec<Self> = bodies
+            .iter()
+            .map(|body| Self {
+                id: body.id,
+                mass: body.mass,
+                position: body.position,
+                velocity: update_bodies.movements[body.index],
+                acceleration: if has_accelerations {
+                    update_bodies.forces[body.index]
+                } else {
+                    DVec3::ZERO
+                },
+                key: body.key,
+            })
+            .collect();
+        rows.sort_by_key(|row| row.id);
+        rows
+    }
+
+    fn floats(&self) -> [f64; 10] {
+        [
+            self.mass,
+            self.position.x,
+            self.position.y,
+            self.position.z,
+            self.velocity.x,
+            self.velocity.y,
+            self.velocity.z,
+            self.acceleration.x,
+            self.acceleration.y,
+            self.acceleration.z,
+        ]
+    }
+}
+
+const FLOAT_COLUMNS: [&str; 10] = ["mass", "x", "y", "z", "vx", "vy", "vz", "ax", "ay", "az"];
+
+/// Floats get written with all their digits, so that reading them gives back the same bits.
+pub fn write_csv(rows: &[ExportRow], include_key: bool, writer: &mut impl Write) -> io::Result<()> {
+    write!(writer, "id,{}", FLOAT_COLUMNS.join(","))?;
+    if include_key {
+        write!(writer, ",key_high,key_low")?;
+    }
+    writeln!(writer)?;
+
+    for row in rows {
+        write!(writer, "{}", row.id)?;
+        for value in row.floats() {
+            write!(writer, ",{:e}", value)?;
+        }
+        if include_key {
+            write!(writer, ",{},{}", (row.key >> 64) as u64, row.key as u64)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Version 1.0 of the [`.npy` format](https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html),
+/// a little-endian structured array with one field per column.
+pub fn write_npy(rows: &[ExportRow], include_key: bool, writer: &mut impl Write) -> io::Result<()> {
+    let mut descr = String::from("[('id', '<u8')");
+    for column in FLOAT_COLUMNS {
+        descr += &format!(", ('{}', '<f8')", column);
+    }
+    if include_key {
+        descr += ", ('key_high', '<u8'), ('key_low', '<u8')";
+    }
+    descr += "]";
+    let mut header = format!(
+        "{{'descr': {}, 'fortran_order': False, 'shape': ({},), }}",
+        descr,
+        rows.len()
+    );
+    // The data has to start at a multiple of 64 bytes, after the magic, the version and the header length
+    let unpadded = 6 + 2 + 2 + header.len() + 1;
+    header += &" ".repeat(unpadded.next_multiple_of(64) - unpadded);
+    header += "\n";
+    let header_length = u16::try_from(header.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Header too long"))?;
+
+    writer.write_all(b"\x93NUMPY")?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&header_length.to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+
+    for row in rows {
+        writer.write_all(&row.id.to_le_bytes())?;
+        for value in row.floats() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        if include_key {
+            writer.write_all(&((row.key >> 64) as u64).to_le_bytes())?;
+            writer.write_all(&(row.key as u64).to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `bodies_<step>.<extension>` into a directory every few steps.
+#[derive(Clone, Debug)]
+pub struct Exporter {
+    pub directory: PathBuf,
+    pub format: ExportFormat,
+    /// 1 for every step
+    pub every: u64,
+    pub include_key: bool,
+}
+
+impl Exporter {
+    pub fn new(directory: impl Into<PathBuf>, format: ExportFormat, every: u64) -> Self {
+        assert!(every > 0);
+        Self {
+            directory: directory.into(),
+            format,
+            every,
+            include_key: false,
+        }
+    }
+
+    /// The path of the written file, or `None` if this isn't a step to export.
+    pub fn export(
+        &self,
+        update_bodies: &UpdateBodies,
+        bodies: &[CelestialBody],
+    ) -> io::Result<Option<PathBuf>> {
+        if !update_bodies.step.is_multiple_of(self.every) {
+            return Ok(None);
+        }
+        let path = self.directory.join(format!(
+            "bodies_{:06}.{}",
+            update_bodies.step,
+            self.format.extension()
+        ));
+        self.save(update_bodies, bodies, &path)?;
+        Ok(Some(path))
+    }
+
+    /// Writes the current step regardless of the cadence.
+    pub fn save(
+        &self,
+        update_bodies: &UpdateBodies,
+        bodies: &[CelestialBody],
+        path: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let rows = ExportRow::collect(update_bodies, bodies);
+        let mut writer = BufWriter::new(File::create(path)?);
+        match self.format {
+            ExportFormat::Csv => write_csv(&rows, self.include_key, &mut writer)?,
+            ExportFormat::Npy => write_npy(&rows, self.include_key, &mut writer)?,
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let rows = [
+            ExportRow {
+                id: 3,
+                mass: 2.5,
+                position: DVec3::new(1.0, -2.0, 0.1),
+                velocity: DVec3::X,
+                acceleration: DVec3::ZERO,
+                key: (7 << 64) | 9,
+            },
+            ExportRow {
+                id: 4,
+                mass: 1e30,
+                position: DVec3::ZERO,
+                velocity: DVec3::ZERO,
+                acceleration: DVec3::Y,
+                key: 0,
+            },
+        ];
+
+        let mut csv = vec![];
+        write_csv(&rows, true, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "id,mass,x,y,z,vx,vy,vz,ax,ay,az,key_high,key_low");
+        assert_eq!(
+            lines[1],
+            "3,2.5e0,1e0,-2e0,1e-1,1e0,0e0,0e0,0e0,0e0,0e0,7,9"
+        );
+        assert_eq!(lines.len(), 3);
+
+        for include_key in [false, true] {
+            let mut npy = vec![];
+            write_npy(&rows, include_key, &mut npy).unwrap();
+            assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+            let header_length = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+            let data_start = 10 + header_length;
+            assert_eq!(data_start % 64, 0);
+            assert_eq!(npy[data_start - 1], b'\n');
+            let header = std::str::from_utf8(&npy[10..data_start]).unwrap();
+            assert!(header.contains("'shape': (2,)"));
+            assert_eq!(header.contains("key_low"), include_key);
+
+            let row_size = if include_key { 13 * 8 } else { 11 * 8 };
+            assert_eq!(npy.len(), data_start + 2 * row_size);
+            let second_row = &npy[data_start + row_size..];
+            assert_eq!(second_row[..8], 4u64.to_le_bytes());
+            assert_eq!(second_row[8..16], 1e30f64.to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use glam::DVec3;
+use rayon::prelude::*;
+
+use crate::{
+    bounding_box::BoundingBox,
+    celestial_body::CelestialBody,
+    cosmic_system::{BoundaryPolicy, CosmicSystem},
+    profiling::span,
+};
+
+/// Groups of bodies that are closer than the linking length to at least one other body of the group,
+/// like the halo finders of cosmological simulations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FriendsOfFriends {
+    /// In m
+    pub linking_length: f64,
+    /// Smaller groups get dropped
+    pub min_members: usize,
+    /// Whether bodies that are too fast to be bound to their group get removed from it.
+    pub unbind: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Group {
+    /// [`CelestialBody::index`] of every body in the group, in ascending order
+    pub members: Vec<usize>,
+    pub mass: f64,
+    pub center_of_mass: DVec3,
+    /// Velocity of the center of mass in m/s
+    pub velocity: DVec3,
+    /// Mass weighted root mean square of the velocities relative to the group, in m/s.
+    /// Over all three axes, so it's `sqrt(3)` times the usual one dimensional dispersion.
+    pub velocity_dispersion: f64,
+    /// Distance in m from the center of mass to the farthest member
+    pub radius: f64,
+}
+
+impl FriendsOfFriends {
+    pub fn new(linking_length: f64) -> Self {
+        Self {
+            linking_length,
+            min_members: 2,
+            unbind: false,
+        }
+    }
+
+    /// Rebuilds the tree, and returns the groups with the heaviest first.
+    /// `velocities` are indexed by [`CelestialBody::index`], like [`crate::simulation::UpdateBodies::movements`].
+    pub fn find(
+        &self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &[DVec3],
+    ) -> Vec<Group> {
+        let _span = span!("Friends of friends");
+        cosmic_system.set_all(bodies);
+        let links: Vec<(usize, usize)> = {
+            let cosmic_system = &*cosmic_system;
+            let bodies = &*bodies;
+            bodies
+                .par_iter()
+                .flat_map_iter(|body| {
+                    let i = body.index;
+                    cosmic_system
+                        .bodies_within(body.position, self.linking_length, bodies)
+                        .into_iter()
+                        .filter(move |&j| i < j)
+                        .map(move |j| (i, j))
+                })
+                .collect()
+        };
+
+        // Union-find over the indices of the bodies
+        let mut parents: Vec<usize> = (0..bodies.len()).collect();
+        fn root(parents: &mut [usize], mut i: usize) -> usize {
+            while parents[i] != i {
+                parents[i] = parents[parents[i]];
+                i = parents[i];
+            }
+            i
+        }
+        for (a, b) in links {
+            let a = root(&mut parents, a);
+            let b = root(&mut parents, b);
+            // The smaller one wins, so that the result doesn't depend on the order of the links
+            parents[a.max(b)] = a.min(b);
+        }
+
+        let mut members: Vec<Vec<CelestialBody>> = vec![vec![]; bodies.len()];
+        for body in bodies.iter() {
+            members[root(&mut parents, body.index)].push(*body);
+        }
+
+        let cosmic_system = &*cosmic_system;
+        let mut groups: Vec<Group> = members
+            .into_par_iter()
+            .filter(|members| members.len() >= self.min_members.max(1))
+            .filter_map(|mut members| {
+                if self.unbind {
+                    self.remove_unbound(cosmic_system, &mut members, velocities);
+                    if members.len() < self.min_members.max(1) {
+                        return None;
+                    }
+                }
+                Some(Group::new(&members, velocities))
+            })
+            .collect();
+        groups.sort_by(|a, b| b.mass.total_cmp(&a.mass));
+        groups
+    }
+
+    /// Repeatedly removes the bodies with a positive energy relative to the group,
+    /// where the potential only comes from the group itself, with the same settings as the given tree.
+    /// Only the most unbound quarter goes at a time, because the velocity of the group changes with every removed body.
+    fn remove_unbound(
+        &self,
+        settings: &CosmicSystem,
+        members: &mut Vec<CelestialBody>,
+        velocities: &[DVec3],
+    ) {
+        let mut tree = CosmicSystem::new(BoundingBox::new(DVec3::ZERO, DVec3::ONE), members.len());
+        tree.set_boundary_policy(BoundaryPolicy::Fit);
+        tree.set_softening(*settings.softening());
+        tree.set_theta(settings.theta());
+        tree.set_opening_criterion(*settings.opening_criterion());
+        tree.set_multipole_order(settings.multipole_order());
+
+        while members.len() >= self.min_members.max(1) {
+            let group = Group::new(members, velocities);
+            tree.set_all(members);
+            let mut unbound: Vec<(f64, usize)> = members
+                .iter()
+                .enumerate()
+                .map(|(i, body)| {
+                    let (_, potential) =
+                        tree.gravitational_force_and_potential_zero_mass(body, members, 0.0);
+                    let kinetic = 0.5 * velocities[body.index].distance_squared(group.velocity);
+                    (kinetic + potential, i)
+                })
+                .filter(|(energy, _)| *energy > 0.0)
+                .collect();
+            if unbound.is_empty() {
+                return;
+            }
+            unbound.sort_by(|a, b| b.0.total_cmp(&a.0));
+            unbound.truncate((members.len() / 4).max(1));
+
+            let mut removed: Vec<usize> = unbound.into_iter().map(|(_, i)| i).collect();
+            removed.sort_unstable();
+            for i in removed.into_iter().rev() {
+                members.swap_remove(i);
+            }
+        }
+    }
+}
+
+impl Group {
+    fn new(members: &[CelestialBody], velocities: &[DVec3]) -> Self {
+        let mut mass = 0.0;
+        let mut weighted_position = DVec3::ZERO;
+        let mut momentum = DVec3::ZERO;
+        for body in members {
+            mass += body.mass;
+            weighted_position += body.position * body.mass;
+            momentum += velocities[body.index] * body.mass;
+        }
+        let center_of_mass = weighted_position / mass;
+        let velocity = momentum / mass;
+
+        let mut weighted_dispersion = 0.0;
+        let mut radius_squared: f64 = 0.0;
+        for body in members {
+            weighted_dispersion += body.mass * velocities[body.index].distance_squared(velocity);
+            radius_squared = radius_squared.max(body.position.distance_squared(center_of_mass));
+        }
+
+        let mut indices: Vec<usize> = members.iter().map(|body| body.index).collect();
+        indices.sort_unstable();
+        Self {
+            members: indices,
+            mass,
+            center_of_mass,
+            velocity,
+            velocity_dispersion: (weighted_dispersion / mass).sqrt(),
+            radius: radius_squared.sqrt(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_two_clumps() {
+        let mut bodies = vec![];
+        // Two clumps of 5 bodies, 1 m apart, and a lone body
+        for (clump, center) in [DVec3::ZERO, DVec3::X * 50.0].into_iter().enumerate() {
+            for i in 0..5 {
+                let index = clump * 5 + i;
+                let position = center + DVec3::new(i as f64, 0.0, 0.0);
+                bodies.push(CelestialBody::new(
+                    index,
+                    1e9 * (clump + 1) as f64,
+                    position,
+                ));
+            }
+        }
+        bodies.push(CelestialBody::new(10, 1e9, DVec3::Y * 50.0));
+        let mut velocities = vec![DVec3::ZERO; bodies.len()];
+        // Way too fast to stay in the first clump
+        velocities[4] = DVec3::Y * 10.0;
+
+        let mut cosmic_system = CosmicSystem::new(
+            BoundingBox::new(DVec3::ONE * -100.0, DVec3::ONE * 100.0),
+            11,
+        );
+        let mut friends_of_friends = FriendsOfFriends::new(1.5);
+        let groups = friends_of_friends.find(&mut cosmic_system, &mut bodies, &velocities);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].members, [5, 6, 7, 8, 9]);
+        assert_eq!(groups[0].center_of_mass, DVec3::new(52.0, 0.0, 0.0));
+        assert_eq!(groups[0].radius, 2.0);
+        assert_eq!(groups[1].members, [0, 1, 2, 3, 4]);
+        assert!(groups[1].velocity_dispersion > 0.0);
+
+        friends_of_friends.unbind = true;
+        let groups = friends_of_friends.find(&mut cosmic_system, &mut bodies, &velocities);
+        assert_eq!(groups[0].members, [5, 6, 7, 8, 9]);
+        assert_eq!(groups[1].members, [0, 1, 2, 3]);
+        assert_eq!(groups[1].velocity_dispersion, 0.0);
+    }
+}
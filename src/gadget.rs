@@ -0,0 +1,821 @@
+//! Snapshots in the binary formats 1 and 2 of GADGET, for initial conditions from other codes and for comparing with them.
+//!
+//! Both formats are Fortran records, each with its length in bytes before and after it.
+//! Format 1 has the blocks in a fixed order, format 2 puts a record with the name of the block before each block.
+//! Only the header, the positions, the velocities, the ids and the masses get read, other blocks get skipped.
+//! Positions and velocities can be single or double precision, ids 32 or 64 bit, and files in either byte order.
+//! Written files are little-endian with single precision, like GADGET writes them by default.
+//!
+//! The values in the files are in the internal units of the run that wrote them, see [`GadgetUnits`].
+//! Cosmological snapshots are read as they are: the positions stay comoving and the velocities keep their factor of `sqrt(a)`.
+//! Snapshots that got split into several files have to be read one file at a time.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+};
+
+use glam::DVec3;
+
+use crate::{
+    bounding_box::BoundingBox,
+    celestial_body::CelestialBody,
+    celestial_body_extensions::{CelestialBodyDrawing, BLUE, CYAN, GREEN, MAGENTA, PINK, RED},
+    cosmic_system::{BoundaryPolicy, CosmicSystem},
+    simulation::{CreateBodiesResult, UpdateBodies, AU},
+    snapshot::invalid_data,
+};
+
+pub const KILOPARSEC: f64 = 3.085678e19;
+pub const SOLAR_MASS: f64 = 1.989e30;
+
+/// The 6 particle types of GADGET: gas, halo, disk, bulge, stars and boundary
+pub const PARTICLE_TYPES: usize = 6;
+
+const HEADER_SIZE: usize = 256;
+const MASS_BLOCK: [u8; 4] = *b"MASS";
+const FORMAT_1_BLOCKS: [[u8; 4]; 4] = [*b"HEAD", *b"POS ", *b"VEL ", *b"ID  "];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GadgetFormat {
+    One,
+    #[default]
+    Two,
+}
+
+/// The internal units of a GADGET run, in SI
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GadgetUnits {
+    /// In m
+    pub length: f64,
+    /// In kg
+    pub mass: f64,
+    /// In m/s
+    pub velocity: f64,
+}
+
+impl GadgetUnits {
+    /// The default of GADGET: kpc, 10^10 solar masses and km/s
+    pub const GALACTIC: Self = Self {
+        length: KILOPARSEC,
+        mass: 1e10 * SOLAR_MASS,
+        velocity: 1e3,
+    };
+
+    /// Astronomical units, solar masses and km/s, for planetary systems
+    pub const SOLAR_SYSTEM: Self = Self {
+        length: AU,
+        mass: SOLAR_MASS,
+        velocity: 1e3,
+    };
+
+    pub const SI: Self = Self {
+        length: 1.0,
+        mass: 1.0,
+        velocity: 1.0,
+    };
+
+    /// For runs that have their lengths and masses in units of `1 / h`, like kpc/h
+    pub fn with_hubble_param(self, hubble_param: f64) -> Self {
+        Self {
+            length: self.length / hubble_param,
+            mass: self.mass / hubble_param,
+            velocity: self.velocity,
+        }
+    }
+
+    /// In s
+    pub fn time(&self) -> f64 {
+        self.length / self.velocity
+    }
+
+    /// [`crate::simulation::G`] in these units, about 43007 for [`GadgetUnits::GALACTIC`]
+    pub fn gravitational_constant(&self) -> f64 {
+        crate::simulation::G * self.mass * self.time().powi(2) / self.length.powi(3)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GadgetParticle {
+    pub id: u64,
+    /// From 0 to 5, see [`PARTICLE_TYPES`]
+    pub particle_type: u8,
+    /// In kg
+    pub mass: f64,
+    /// In m
+    pub position: DVec3,
+    /// In m/s
+    pub velocity: DVec3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GadgetSnapshot {
+    /// In s. Cosmological runs store the scale factor here instead, which is then in units of [`GadgetUnits::time`].
+    pub time: f64,
+    pub redshift: f64,
+    /// In m, 0 if the run wasn't periodic
+    pub box_size: f64,
+    pub omega0: f64,
+    pub omega_lambda: f64,
+    pub hubble_param: f64,
+    /// Ordered by type, like in the file
+    pub particles: Vec<GadgetParticle>,
+}
+
+impl GadgetSnapshot {
+    /// The current state of a run, with all bodies as halo particles.
+    pub fn capture(update_bodies: &UpdateBodies, bodies: &[CelestialBody]) -> Self {
+        let mut particles: Vec<GadgetParticle> = bodies
+            .iter()
+            .map(|body| GadgetParticle {
+                id: body.id,
+                particle_type: 1,
+                mass: body.mass,
+                position: body.position,
+                velocity: update_bodies.movements[body.index],
+            })
+            .collect();
+        particles.sort_by_key(|particle| particle.id);
+
+        Self {
+            time: update_bodies.time,
+            redshift: 0.0,
+            box_size: 0.0,
+            omega0: 0.0,
+            omega_lambda: 0.0,
+            hubble_param: 1.0,
+            particles,
+        }
+    }
+
+    /// A system with all particles, in the order of the file.
+    /// Its bounding box is the periodic box, or the cube around the particles, and it grows when they leave it.
+    pub fn to_result(&self) -> CreateBodiesResult {
+        let predefined_colors = [RED, BLUE, CYAN, MAGENTA, PINK, GREEN];
+        let mut bodies = Vec::with_capacity(self.particles.len());
+        let mut bodies_drawing = Vec::with_capacity(self.particles.len());
+        let mut movements = Vec::with_capacity(self.particles.len());
+        let mut bounding_box = BoundingBox::EMPTY;
+        for (index, particle) in self.particles.iter().enumerate() {
+            let mut body = CelestialBody::new(index, particle.mass, particle.position);
+            body.id = particle.id;
+            bodies.push(body);
+            bodies_drawing.push(CelestialBodyDrawing {
+                color: predefined_colors[particle.particle_type as usize % PARTICLE_TYPES],
+                radius: 1e6,
+            });
+            movements.push(particle.velocity);
+            bounding_box.expand_to_include(particle.position);
+        }
+        if self.box_size > 0.0 {
+            bounding_box = BoundingBox::new(DVec3::ZERO, DVec3::splat(self.box_size));
+        } else if bodies.is_empty() {
+            bounding_box = BoundingBox::from_center(DVec3::ZERO, DVec3::splat(AU));
+        } else {
+            // A cube, because the particles can be in a plane or all in the same spot
+            let mut side_length = bounding_box.side_length();
+            if side_length <= 0.0 {
+                side_length = 2.0 * AU;
+            }
+            bounding_box =
+                BoundingBox::from_center(bounding_box.center(), DVec3::splat(side_length * 0.5));
+        }
+
+        let mut cosmic_system = CosmicSystem::new(bounding_box, bodies.len());
+        cosmic_system.set_boundary_policy(BoundaryPolicy::Grow);
+        CreateBodiesResult {
+            cosmic_system,
+            bodies,
+            bodies_drawing,
+            movements,
+        }
+    }
+
+    pub fn read(reader: &mut impl Read, units: &GadgetUnits) -> io::Result<Self> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        let mut records = Records::new(&bytes)?;
+        let decoder = Decoder {
+            big_endian: records.big_endian,
+        };
+
+        let mut header = None;
+        let mut positions = None;
+        let mut velocities = None;
+        let mut ids = None;
+        let mut masses = None;
+        let mut block_index = 0;
+        while let Some((label, data)) = records.next_block()? {
+            let label = match label {
+                Some(label) => label,
+                None => match block_index {
+                    0..=3 => FORMAT_1_BLOCKS[block_index],
+                    // Only there if some type has no mass in the header
+                    4 if header
+                        .as_ref()
+                        .is_some_and(|header: &Header| header.variable_mass_count() > 0) =>
+                    {
+                        MASS_BLOCK
+                    }
+                    _ => break,
+                },
+            };
+            block_index += 1;
+
+            let count = header.as_ref().map_or(0, Header::particle_count);
+            match &label {
+                b"HEAD" => header = Some(Header::decode(data, &decoder)?),
+                b"POS " => positions = Some(decoder.vec3s(data, count, "POS")?),
+                b"VEL " => velocities = Some(decoder.vec3s(data, count, "VEL")?),
+                b"ID  " => ids = Some(decoder.ids(data, count)?),
+                b"MASS" => {
+                    let count = header.as_ref().map_or(0, Header::variable_mass_count);
+                    masses = Some(decoder.floats(data, count, "MASS")?);
+                }
+                _ => {}
+            }
+        }
+
+        let header = header.ok_or_else(|| missing("HEAD"))?;
+        let positions = positions.ok_or_else(|| missing("POS"))?;
+        let velocities = velocities.ok_or_else(|| missing("VEL"))?;
+        let ids = ids.ok_or_else(|| missing("ID"))?;
+        let masses = match masses {
+            Some(masses) => masses,
+            None if header.variable_mass_count() == 0 => vec![],
+            None => return Err(missing("MASS")),
+        };
+
+        let mut particles = Vec::with_capacity(positions.len());
+        let mut masses = masses.into_iter();
+        for (particle_type, &count) in header.particle_counts.iter().enumerate() {
+            for _ in 0..count {
+                let index = particles.len();
+                let mass = if header.masses[particle_type] == 0.0 {
+                    masses.next().ok_or_else(|| missing("MASS"))?
+                } else {
+                    header.masses[particle_type]
+                };
+                particles.push(GadgetParticle {
+                    id: ids[index],
+                    particle_type: particle_type as u8,
+                    mass: mass * units.mass,
+                    position: positions[index] * units.length,
+                    velocity: velocities[index] * units.velocity,
+                });
+            }
+        }
+
+        Ok(Self {
+            time: header.time * units.time(),
+            redshift: header.redshift,
+            box_size: header.box_size * units.length,
+            omega0: header.omega0,
+            omega_lambda: header.omega_lambda,
+            hubble_param: header.hubble_param,
+            particles,
+        })
+    }
+
+    /// The particles get grouped by type. Types where all particles have the same mass only have it in the header.
+    pub fn write(
+        &self,
+        writer: &mut impl Write,
+        units: &GadgetUnits,
+        format: GadgetFormat,
+    ) -> io::Result<()> {
+        self.write_with(writer, units, format, false)
+    }
+
+    fn write_with(
+        &self,
+        writer: &mut impl Write,
+        units: &GadgetUnits,
+        format: GadgetFormat,
+        big_endian: bool,
+    ) -> io::Result<()> {
+        let mut particles: Vec<&GadgetParticle> = self.particles.iter().collect();
+        particles.sort_by_key(|particle| particle.particle_type);
+        if particles
+            .last()
+            .is_some_and(|particle| particle.particle_type as usize >= PARTICLE_TYPES)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "GADGET only has 6 particle types",
+            ));
+        }
+
+        let mut header = Header {
+            particle_counts: [0; PARTICLE_TYPES],
+            masses: [0.0; PARTICLE_TYPES],
+            time: self.time / units.time(),
+            redshift: self.redshift,
+            box_size: self.box_size / units.length,
+            omega0: self.omega0,
+            omega_lambda: self.omega_lambda,
+            hubble_param: self.hubble_param,
+        };
+        for particle_type in 0..PARTICLE_TYPES {
+            let mut of_type = particles
+                .iter()
+                .filter(|particle| particle.particle_type as usize == particle_type);
+            if let Some(first) = of_type.next() {
+                let count = 1 + of_type.clone().count();
+                header.particle_counts[particle_type] = count as u32;
+                if of_type.all(|particle| particle.mass == first.mass) {
+                    header.masses[particle_type] = first.mass / units.mass;
+                }
+            }
+        }
+
+        let encoder = Encoder { big_endian };
+        let mut block_writer = BlockWriter {
+            writer,
+            encoder,
+            format,
+        };
+        block_writer.write(b"HEAD", &header.encode(&encoder))?;
+
+        let mut data = vec![];
+        for particle in &particles {
+            for component in (particle.position / units.length).to_array() {
+                encoder.f32(&mut data, component as f32);
+            }
+        }
+        block_writer.write(b"POS ", &data)?;
+
+        data.clear();
+        for particle in &particles {
+            for component in (particle.velocity / units.velocity).to_array() {
+                encoder.f32(&mut data, component as f32);
+            }
+        }
+        block_writer.write(b"VEL ", &data)?;
+
+        data.clear();
+        let long_ids = particles
+            .iter()
+            .any(|particle| particle.id > u32::MAX as u64);
+        for particle in &particles {
+            if long_ids {
+                encoder.u64(&mut data, particle.id);
+            } else {
+                encoder.u32(&mut data, particle.id as u32);
+            }
+        }
+        block_writer.write(b"ID  ", &data)?;
+
+        if header.variable_mass_count() > 0 {
+            data.clear();
+            for particle in &particles {
+                if header.masses[particle.particle_type as usize] == 0.0 {
+                    encoder.f32(&mut data, (particle.mass / units.mass) as f32);
+                }
+            }
+            block_writer.write(&MASS_BLOCK, &data)?;
+        }
+
+        // GADGET expects the internal energy of the gas in initial conditions
+        if header.particle_counts[0] > 0 {
+            data.clear();
+            for _ in 0..header.particle_counts[0] {
+                encoder.f32(&mut data, 0.0);
+            }
+            block_writer.write(b"U   ", &data)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(
+        &self,
+        path: impl AsRef<Path>,
+        units: &GadgetUnits,
+        format: GadgetFormat,
+    ) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer, units, format)?;
+        writer.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>, units: &GadgetUnits) -> io::Result<Self> {
+        Self::read(&mut File::open(path)?, units)
+    }
+}
+
+/// The parts of the 256 bytes of the header that matter here, in internal units
+struct Header {
+    particle_counts: [u32; PARTICLE_TYPES],
+    /// 0 for types with their masses in the MASS block
+    masses: [f64; PARTICLE_TYPES],
+    time: f64,
+    redshift: f64,
+    box_size: f64,
+    omega0: f64,
+    omega_lambda: f64,
+    hubble_param: f64,
+}
+
+impl Header {
+    fn particle_count(&self) -> usize {
+        self.particle_counts
+            .iter()
+            .map(|&count| count as usize)
+            .sum()
+    }
+
+    fn variable_mass_count(&self) -> usize {
+        (0..PARTICLE_TYPES)
+            .filter(|&particle_type| self.masses[particle_type] == 0.0)
+            .map(|particle_type| self.particle_counts[particle_type] as usize)
+            .sum()
+    }
+
+    fn decode(data: &[u8], decoder: &Decoder) -> io::Result<Self> {
+        if data.len() != HEADER_SIZE {
+            return Err(invalid_data(format!(
+                "The header has {} bytes instead of {}",
+                data.len(),
+                HEADER_SIZE
+            )));
+        }
+        let u32_at = |offset: usize| decoder.u32(&data[offset..offset + 4]);
+        let f64_at = |offset: usize| decoder.f64(&data[offset..offset + 8]);
+        Ok(Self {
+            particle_counts: std::array::from_fn(|i| u32_at(4 * i)),
+            masses: std::array::from_fn(|i| f64_at(24 + 8 * i)),
+            time: f64_at(72),
+            redshift: f64_at(80),
+            box_size: f64_at(128),
+            omega0: f64_at(136),
+            omega_lambda: f64_at(144),
+            hubble_param: f64_at(152),
+        })
+    }
+
+    fn encode(&self, encoder: &Encoder) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE);
+        for count in self.particle_counts {
+            encoder.u32(&mut data, count);
+        }
+        for mass in self.masses {
+            encoder.f64(&mut data, mass);
+        }
+        encoder.f64(&mut data, self.time);
+        encoder.f64(&mut data, self.redshift);
+        // Star formation and feedback flags
+        encoder.u32(&mut data, 0);
+        encoder.u32(&mut data, 0);
+        // The total counts over all files, which is just this one
+        for count in self.particle_counts {
+            encoder.u32(&mut data, count);
+        }
+        // Cooling flag and number of files
+        encoder.u32(&mut data, 0);
+        encoder.u32(&mut data, 1);
+        for value in [
+            self.box_size,
+            self.omega0,
+            self.omega_lambda,
+            self.hubble_param,
+        ] {
+            encoder.f64(&mut data, value);
+        }
+        // The remaining flags, the high words of the total counts and the padding
+        data.resize(HEADER_SIZE, 0);
+        data
+    }
+}
+
+fn missing(block: &str) -> io::Error {
+    invalid_data(format!("The {} block is missing", block))
+}
+
+/// The name of a block, which only format 2 has, and its data
+type Block<'a> = (Option<[u8; 4]>, &'a [u8]);
+
+/// Splits a file into its blocks
+struct Records<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+    format: GadgetFormat,
+}
+
+impl<'a> Records<'a> {
+    /// Recognizes the format and the byte order by the length of the first record,
+    /// which is the header in format 1 and the name of the header in format 2.
+    fn new(bytes: &'a [u8]) -> io::Result<Self> {
+        let first: [u8; 4] = bytes
+            .get(..4)
+            .and_then(|first| first.try_into().ok())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let (big_endian, format) = match (u32::from_le_bytes(first), u32::from_be_bytes(first)) {
+            (256, _) => (false, GadgetFormat::One),
+            (8, _) => (false, GadgetFormat::Two),
+            (_, 256) => (true, GadgetFormat::One),
+            (_, 8) => (true, GadgetFormat::Two),
+            _ => return Err(invalid_data("Not a GADGET snapshot".to_string())),
+        };
+        Ok(Self {
+            bytes,
+            big_endian,
+            format,
+        })
+    }
+
+    fn next_record(&mut self) -> io::Result<&'a [u8]> {
+        let decoder = Decoder {
+            big_endian: self.big_endian,
+        };
+        let length = self
+            .bytes
+            .get(..4)
+            .map(|length| decoder.u32(length) as usize)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let end = 4 + length;
+        let data = self
+            .bytes
+            .get(4..end)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let trailing = self
+            .bytes
+            .get(end..end + 4)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if decoder.u32(trailing) as usize != length {
+            return Err(invalid_data("Broken record".to_string()));
+        }
+        self.bytes = &self.bytes[end + 4..];
+        Ok(data)
+    }
+
+    /// `None` at the end of the file
+    fn next_block(&mut self) -> io::Result<Option<Block<'a>>> {
+        if self.bytes.is_empty() {
+            return Ok(None);
+        }
+        let label = match self.format {
+            GadgetFormat::One => None,
+            GadgetFormat::Two => {
+                let record = self.next_record()?;
+                if record.len() != 8 {
+                    return Err(invalid_data("Broken block name".to_string()));
+                }
+                Some([record[0], record[1], record[2], record[3]])
+            }
+        };
+        Ok(Some((label, self.next_record()?)))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Decoder {
+    big_endian: bool,
+}
+
+impl Decoder {
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn u64(&self, bytes: &[u8]) -> u64 {
+        let bytes = bytes.try_into().unwrap();
+        if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        }
+    }
+
+    fn f64(&self, bytes: &[u8]) -> f64 {
+        f64::from_bits(self.u64(bytes))
+    }
+
+    /// Single or double precision, depending on the size of the block
+    fn floats(&self, data: &[u8], count: usize, block: &str) -> io::Result<Vec<f64>> {
+        if data.len() == 4 * count {
+            Ok(data
+                .chunks_exact(4)
+                .map(|bytes| f32::from_bits(self.u32(bytes)) as f64)
+                .collect())
+        } else if data.len() == 8 * count {
+            Ok(data.chunks_exact(8).map(|bytes| self.f64(bytes)).collect())
+        } else {
+            Err(wrong_size(block, data.len(), count))
+        }
+    }
+
+    fn vec3s(&self, data: &[u8], count: usize, block: &str) -> io::Result<Vec<DVec3>> {
+        Ok(self
+            .floats(data, 3 * count, block)?
+            .chunks_exact(3)
+            .map(DVec3::from_slice)
+            .collect())
+    }
+
+    fn ids(&self, data: &[u8], count: usize) -> io::Result<Vec<u64>> {
+        if data.len() == 4 * count {
+            Ok(data
+                .chunks_exact(4)
+                .map(|bytes| self.u32(bytes) as u64)
+                .collect())
+        } else if data.len() == 8 * count {
+            Ok(data.chunks_exact(8).map(|bytes| self.u64(bytes)).collect())
+        } else {
+            Err(wrong_size("ID", data.len(), count))
+        }
+    }
+}
+
+fn wrong_size(block: &str, size: usize, count: usize) -> io::Error {
+    invalid_data(format!(
+        "The {} block has {} bytes, which doesn't fit {} values",
+        block, size, count
+    ))
+}
+
+#[derive(Clone, Copy)]
+struct Encoder {
+    big_endian: bool,
+}
+
+impl Encoder {
+    fn u32(&self, data: &mut Vec<u8>, value: u32) {
+        data.extend(if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        });
+    }
+
+    fn u64(&self, data: &mut Vec<u8>, value: u64) {
+        data.extend(if self.big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        });
+    }
+
+    fn f32(&self, data: &mut Vec<u8>, value: f32) {
+        self.u32(data, value.to_bits());
+    }
+
+    fn f64(&self, data: &mut Vec<u8>, value: f64) {
+        self.u64(data, value.to_bits());
+    }
+}
+
+struct BlockWriter<'a, W: Write> {
+    writer: &'a mut W,
+    encoder: Encoder,
+    format: GadgetFormat,
+}
+
+impl<W: Write> BlockWriter<'_, W> {
+    fn record(&mut self, data: &[u8]) -> io::Result<()> {
+        let length = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Block too large"))?;
+        let mut marker = Vec::with_capacity(4);
+        self.encoder.u32(&mut marker, length);
+        self.writer.write_all(&marker)?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&marker)
+    }
+
+    fn write(&mut self, label: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        if self.format == GadgetFormat::Two {
+            let mut name = label.to_vec();
+            // The size of the following record, including its markers
+            self.encoder.u32(&mut name, data.len() as u32 + 8);
+            self.record(&name)?;
+        }
+        self.record(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let units = GadgetUnits::GALACTIC;
+        assert!((units.gravitational_constant() / 43007.1 - 1.0).abs() < 1e-3);
+
+        let particle = |id, particle_type, mass: f64, x: f64| GadgetParticle {
+            id,
+            particle_type,
+            mass: mass * units.mass,
+            position: DVec3::new(x, -x, 0.5) * units.length,
+            velocity: DVec3::new(0.0, 200.0, x) * units.velocity,
+        };
+        let mut snapshot = GadgetSnapshot {
+            time: 2.0 * units.time(),
+            redshift: 0.0,
+            box_size: 100.0 * units.length,
+            omega0: 0.3,
+            omega_lambda: 0.7,
+            hubble_param: 0.7,
+            particles: vec![
+                particle(10, 0, 1.0, 1.0),
+                particle(11, 1, 2.0, 2.0),
+                particle(12, 1, 2.0, 3.0),
+                particle(13, 2, 1.0, 4.0),
+                particle(14, 2, 3.0, 5.0),
+            ],
+        };
+
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-6 * a.abs().max(b.abs());
+        for long_ids in [false, true] {
+            if long_ids {
+                snapshot.particles[0].id = 1 << 40;
+            }
+            for format in [GadgetFormat::One, GadgetFormat::Two] {
+                for big_endian in [false, true] {
+                    let mut bytes = vec![];
+                    snapshot
+                        .write_with(&mut bytes, &units, format, big_endian)
+                        .unwrap();
+                    let read = GadgetSnapshot::read(&mut bytes.as_slice(), &units).unwrap();
+
+                    assert!(close(read.time, snapshot.time));
+                    assert!(close(read.box_size, snapshot.box_size));
+                    assert_eq!(read.omega0, snapshot.omega0);
+                    assert_eq!(read.omega_lambda, snapshot.omega_lambda);
+                    assert_eq!(read.hubble_param, snapshot.hubble_param);
+                    assert_eq!(read.particles.len(), snapshot.particles.len());
+                    for (read, particle) in read.particles.iter().zip(&snapshot.particles) {
+                        assert_eq!(read.id, particle.id);
+                        assert_eq!(read.particle_type, particle.particle_type);
+                        assert!(close(read.mass, particle.mass));
+                        for i in 0..3 {
+                            assert!(close(read.position[i], particle.position[i]));
+                            assert!(close(read.velocity[i], particle.velocity[i]));
+                        }
+                    }
+                }
+            }
+        }
+
+        let result = snapshot.to_result();
+        assert_eq!(result.bodies.len(), 5);
+        assert_eq!(result.bodies[0].id, 1 << 40);
+        assert_eq!(result.movements[1], snapshot.particles[1].velocity);
+
+        let mut bytes = vec![];
+        snapshot
+            .write(&mut bytes, &units, GadgetFormat::Two)
+            .unwrap();
+        let error = GadgetSnapshot::read(&mut &bytes[..bytes.len() - 1], &units).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_flat_file_gets_a_cube() {
+        let units = GadgetUnits::SI;
+        let particle = |id, x: f64, y: f64| GadgetParticle {
+            id,
+            particle_type: 1,
+            mass: 1e20,
+            position: DVec3::new(x, y, 0.0),
+            velocity: DVec3::ZERO,
+        };
+        let mut snapshot = GadgetSnapshot {
+            time: 0.0,
+            redshift: 0.0,
+            box_size: 0.0,
+            omega0: 0.0,
+            omega_lambda: 0.0,
+            hubble_param: 1.0,
+            particles: vec![particle(0, -2.0, 0.0), particle(1, 2.0, 1.0)],
+        };
+        for particle_count in [2, 1] {
+            snapshot.particles.truncate(particle_count);
+            let mut bytes = vec![];
+            snapshot
+                .write(&mut bytes, &units, GadgetFormat::Two)
+                .unwrap();
+            let read = GadgetSnapshot::read(&mut bytes.as_slice(), &units).unwrap();
+
+            let mut result = read.to_result();
+            let bounding_box = *result.cosmic_system.bounding_box();
+            let extent = bounding_box.extent();
+            assert!(extent.min_element() > 0.0, "{:?}", bounding_box);
+            assert_eq!(extent.x, extent.y);
+            assert_eq!(extent.x, extent.z);
+            for particle in &snapshot.particles {
+                assert!(bounding_box
+                    .contains_box(&BoundingBox::new(particle.position, particle.position)));
+            }
+
+            // Doesn't have to grow
+            result.cosmic_system.set_all(&mut result.bodies);
+            assert_eq!(*result.cosmic_system.bounding_box(), bounding_box);
+        }
+    }
+}
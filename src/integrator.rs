@@ -0,0 +1,383 @@
+use glam::DVec3;
+use rayon::prelude::*;
+
+use crate::{
+    block_timesteps::BlockTimesteps, celestial_body::CelestialBody, cosmic_system::CosmicSystem,
+    profiling::span,
+};
+
+/// Rebuilds the tree and computes the acceleration of every body.
+/// The accelerations are indexed by [`CelestialBody::index`].
+pub fn compute_accelerations(
+    cosmic_system: &mut CosmicSystem,
+    bodies: &mut Vec<CelestialBody>,
+    accelerations: &mut Vec<DVec3>,
+) {
+    compute_accelerations_where(cosmic_system, bodies, accelerations, |_| true);
+}
+
+/// Rebuilds the tree and computes the acceleration of the bodies for which `is_active` returns true.
+/// The other accelerations are left untouched.
+pub fn compute_accelerations_where(
+    cosmic_system: &mut CosmicSystem,
+    bodies: &mut Vec<CelestialBody>,
+    accelerations: &mut Vec<DVec3>,
+    is_active: impl Fn(&CelestialBody) -> bool + Sync,
+) {
+    {
+        let _span = span!("Update tree");
+        cosmic_system.set_all(bodies);
+    }
+
+    // this is the bottleneck, but we only read things from the tree
+    // so we can easily multithread it
+    let _span = span!("Compute forces");
+    let cosmic_system = &*cosmic_system;
+    // The relative opening criterion needs the accelerations from the previous step
+    let has_previous = accelerations.len() == bodies.len();
+    let previous_accelerations = &*accelerations;
+    let computed: Vec<(usize, DVec3)> = bodies
+        .par_iter()
+        .filter(|body| is_active(body))
+        .map(|body| {
+            let previous_acceleration = if has_previous {
+                previous_accelerations[body.index].length()
+            } else {
+                0.0
+            };
+            (
+                body.index,
+                cosmic_system.gravitational_force_zero_mass_relative(
+                    body,
+                    bodies,
+                    previous_acceleration,
+                ),
+            )
+        })
+        .collect();
+    accelerations.resize(bodies.len(), DVec3::ZERO);
+    for (index, acceleration) in computed {
+        accelerations[index] = acceleration;
+    }
+}
+
+/// `velocities += accelerations * dt`
+pub fn kick(bodies: &[CelestialBody], velocities: &mut [DVec3], accelerations: &[DVec3], dt: f64) {
+    for body in bodies {
+        velocities[body.index] += accelerations[body.index] * dt;
+    }
+}
+
+/// `positions += velocities * dt`
+pub fn drift(bodies: &mut [CelestialBody], velocities: &[DVec3], dt: f64) {
+    for body in bodies {
+        body.update(velocities[body.index], dt);
+    }
+}
+
+/// A scheme for advancing the bodies through time.
+pub trait Integrator {
+    /// Advances the bodies and their velocities by `dt` seconds.
+    ///
+    /// `velocities` and `accelerations` are indexed by [`CelestialBody::index`].
+    /// On return, `accelerations` holds the result of the last force evaluation.
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    );
+
+    /// Forgets everything that was carried over from the previous step.
+    /// Has to be called when the bodies were changed outside of [`Integrator::step`].
+    fn reset(&mut self) {}
+}
+
+/// First order. Kick with the current forces, then drift.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SemiImplicitEuler;
+
+impl Integrator for SemiImplicitEuler {
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    ) {
+        compute_accelerations(cosmic_system, bodies, accelerations);
+
+        // has to be done separately, because you can't move bodies while still computing gravity
+        let _span = span!("Update bodies");
+        kick(bodies, velocities, accelerations, dt);
+        drift(bodies, velocities, dt);
+    }
+}
+
+/// Second order, symplectic. Kick half a step, drift a full step, kick half a step.
+/// The forces at the end of a step are reused at the start of the next one,
+/// so there is only one force evaluation per step.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LeapfrogKdk {
+    /// Whether the accelerations match the current positions.
+    pub primed: bool,
+}
+
+impl Integrator for LeapfrogKdk {
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    ) {
+        if !self.primed {
+            compute_accelerations(cosmic_system, bodies, accelerations);
+        }
+        {
+            let _span = span!("Update bodies");
+            kick(bodies, velocities, accelerations, 0.5 * dt);
+            drift(bodies, velocities, dt);
+        }
+        compute_accelerations(cosmic_system, bodies, accelerations);
+        kick(bodies, velocities, accelerations, 0.5 * dt);
+        self.primed = true;
+    }
+
+    fn reset(&mut self) {
+        self.primed = false;
+    }
+}
+
+/// Second order, symplectic. Updates the positions with the current forces,
+/// and the velocities with the average of the old and the new forces.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VelocityVerlet {
+    /// Whether the accelerations match the current positions.
+    pub primed: bool,
+}
+
+impl Integrator for VelocityVerlet {
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    ) {
+        if !self.primed {
+            compute_accelerations(cosmic_system, bodies, accelerations);
+        }
+        {
+            let _span = span!("Update bodies");
+            for body in bodies.iter_mut() {
+                let index = body.index;
+                body.position += velocities[index] * dt + accelerations[index] * (0.5 * dt * dt);
+            }
+        }
+        let old_accelerations = accelerations.clone();
+        compute_accelerations(cosmic_system, bodies, accelerations);
+        for body in bodies.iter() {
+            let index = body.index;
+            velocities[index] += (old_accelerations[index] + accelerations[index]) * (0.5 * dt);
+        }
+        self.primed = true;
+    }
+
+    fn reset(&mut self) {
+        self.primed = false;
+    }
+}
+
+/// Classic fourth order Runge-Kutta. Four force evaluations per step, not symplectic.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rk4;
+
+impl Integrator for Rk4 {
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    ) {
+        // The tree reorders the bodies, so everything is indexed by CelestialBody::index
+        let mut start_positions = vec![DVec3::ZERO; bodies.len()];
+        for body in bodies.iter() {
+            start_positions[body.index] = body.position;
+        }
+        let start_velocities = velocities.to_vec();
+        let mut position_sum = vec![DVec3::ZERO; bodies.len()];
+        let mut velocity_sum = vec![DVec3::ZERO; bodies.len()];
+
+        // k1 is evaluated at the start, k2 and k3 half a step in and k4 a full step in
+        let stages = [(0.5, 1.0), (0.5, 2.0), (1.0, 2.0), (0.0, 1.0)];
+        for (next_fraction, weight) in stages {
+            compute_accelerations(cosmic_system, bodies, accelerations);
+
+            let _span = span!("Update bodies");
+            for body in bodies.iter_mut() {
+                let index = body.index;
+                position_sum[index] += velocities[index] * weight;
+                velocity_sum[index] += accelerations[index] * weight;
+
+                // Prepare the state for the next stage
+                body.position = start_positions[index] + velocities[index] * (next_fraction * dt);
+                velocities[index] =
+                    start_velocities[index] + accelerations[index] * (next_fraction * dt);
+            }
+        }
+
+        for body in bodies.iter_mut() {
+            let index = body.index;
+            body.position = start_positions[index] + position_sum[index] * (dt / 6.0);
+            velocities[index] = start_velocities[index] + velocity_sum[index] * (dt / 6.0);
+        }
+    }
+}
+
+/// Fourth order, symplectic. Three leapfrog steps with carefully chosen lengths,
+/// one of which goes backwards in time.
+/// See Yoshida, "Construction of higher order symplectic integrators" (1990).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Yoshida4;
+
+impl Integrator for Yoshida4 {
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    ) {
+        let cube_root_2 = 2f64.cbrt();
+        let w1 = 1.0 / (2.0 - cube_root_2);
+        let w0 = -cube_root_2 * w1;
+        let drifts = [w1 / 2.0, (w0 + w1) / 2.0, (w0 + w1) / 2.0, w1 / 2.0];
+        let kicks = [w1, w0, w1];
+
+        for (drift_factor, kick_factor) in drifts.iter().zip(kicks) {
+            drift(bodies, velocities, drift_factor * dt);
+            compute_accelerations(cosmic_system, bodies, accelerations);
+            kick(bodies, velocities, accelerations, kick_factor * dt);
+        }
+        drift(bodies, velocities, drifts[3] * dt);
+    }
+}
+
+/// All the integrators that [`crate::simulation::UpdateBodies`] can use.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IntegratorKind {
+    SemiImplicitEuler(SemiImplicitEuler),
+    LeapfrogKdk(LeapfrogKdk),
+    VelocityVerlet(VelocityVerlet),
+    Rk4(Rk4),
+    Yoshida4(Yoshida4),
+    BlockTimesteps(BlockTimesteps),
+}
+
+impl Default for IntegratorKind {
+    fn default() -> Self {
+        IntegratorKind::SemiImplicitEuler(SemiImplicitEuler)
+    }
+}
+
+impl Integrator for IntegratorKind {
+    fn step(
+        &mut self,
+        cosmic_system: &mut CosmicSystem,
+        bodies: &mut Vec<CelestialBody>,
+        velocities: &mut [DVec3],
+        accelerations: &mut Vec<DVec3>,
+        dt: f64,
+    ) {
+        match self {
+            IntegratorKind::SemiImplicitEuler(integrator) => {
+                integrator.step(cosmic_system, bodies, velocities, accelerations, dt)
+            }
+            IntegratorKind::LeapfrogKdk(integrator) => {
+                integrator.step(cosmic_system, bodies, velocities, accelerations, dt)
+            }
+            IntegratorKind::VelocityVerlet(integrator) => {
+                integrator.step(cosmic_system, bodies, velocities, accelerations, dt)
+            }
+            IntegratorKind::Rk4(integrator) => {
+                integrator.step(cosmic_system, bodies, velocities, accelerations, dt)
+            }
+            IntegratorKind::Yoshida4(integrator) => {
+                integrator.step(cosmic_system, bodies, velocities, accelerations, dt)
+            }
+            IntegratorKind::BlockTimesteps(integrator) => {
+                integrator.step(cosmic_system, bodies, velocities, accelerations, dt)
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            IntegratorKind::SemiImplicitEuler(integrator) => integrator.reset(),
+            IntegratorKind::LeapfrogKdk(integrator) => integrator.reset(),
+            IntegratorKind::VelocityVerlet(integrator) => integrator.reset(),
+            IntegratorKind::Rk4(integrator) => integrator.reset(),
+            IntegratorKind::Yoshida4(integrator) => integrator.reset(),
+            IntegratorKind::BlockTimesteps(integrator) => integrator.reset(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{bounding_box::BoundingBox, simulation};
+
+    use super::*;
+
+    /// A light body on a circular orbit should come back to where it started after one period.
+    #[test]
+    fn test_circular_orbit() {
+        let central_mass = 1e30;
+        let radius = 1e11;
+        let speed = (simulation::G * central_mass / radius).sqrt();
+        let period = 2.0 * std::f64::consts::PI * radius / speed;
+        let steps = 500;
+
+        for mut integrator in [
+            IntegratorKind::LeapfrogKdk(Default::default()),
+            IntegratorKind::VelocityVerlet(Default::default()),
+            IntegratorKind::Rk4(Rk4),
+            IntegratorKind::Yoshida4(Yoshida4),
+            IntegratorKind::BlockTimesteps(BlockTimesteps::new(radius * 1e-3, 4)),
+        ] {
+            let mut bodies = vec![
+                CelestialBody::new(0, central_mass, DVec3::ZERO),
+                CelestialBody::new(1, 1.0, DVec3::new(radius, 0.0, 0.0)),
+            ];
+            let mut velocities = vec![DVec3::ZERO, DVec3::new(0.0, speed, 0.0)];
+            let mut accelerations = vec![];
+            let mut cosmic_system = CosmicSystem::new(
+                BoundingBox::new(DVec3::ONE * -2.0 * radius, DVec3::ONE * 2.0 * radius),
+                bodies.len(),
+            );
+
+            for _ in 0..steps {
+                integrator.step(
+                    &mut cosmic_system,
+                    &mut bodies,
+                    &mut velocities,
+                    &mut accelerations,
+                    period / steps as f64,
+                );
+            }
+
+            let orbiting = bodies.iter().find(|body| body.index == 1).unwrap();
+            let error = orbiting.position.distance(DVec3::new(radius, 0.0, 0.0)) / radius;
+            assert!(error < 1e-3, "{:?} is off by {}", integrator, error);
+        }
+    }
+}
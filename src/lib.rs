@@ -1,7 +1,20 @@
+pub mod block_timesteps;
 pub mod bounding_box;
 pub mod celestial_body;
 pub mod celestial_body_extensions;
+pub mod checkpoint;
+pub mod collision;
 pub mod cosmic_system;
+pub mod diagnostics;
+pub mod direct;
+pub mod export;
+pub mod friends_of_friends;
+pub mod gadget;
+pub mod integrator;
+pub mod opening_criterion;
+mod profiling;
+pub(crate) mod random;
 pub mod simulation;
-pub mod vec3_extensions;
+pub mod snapshot;
+pub mod softening;
 pub mod z_order;
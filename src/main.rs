@@ -1,5 +1,9 @@
+// simple_game! checks for features of comfy, which this crate doesn't know about
+#![allow(unexpected_cfgs)]
 use cosmic_system::{
     celestial_body::CelestialBody,
+    celestial_body_extensions::CelestialBodyDrawing,
+    collision::CollisionEvent,
     simulation::{self, CreateBodiesResult, UpdateBodies},
 };
 use std::thread;
@@ -14,6 +18,8 @@ simple_game!("Cosmic System", GameState, setup, update);
 pub struct GameState {
     pub bounding_box: BoundingBox,
     pub bodies: Arc<Mutex<Vec<CelestialBody>>>,
+    /// Indexed by [`CelestialBody::index`], locked after the bodies.
+    pub bodies_drawing: Arc<Mutex<Vec<CelestialBodyDrawing>>>,
     pub particles: Entity,
     pub handle: Option<thread::JoinHandle<()>>,
 }
@@ -26,6 +32,7 @@ impl GameState {
                 DVec3::ONE * 4.0 * simulation::AU,
             ),
             bodies: Default::default(),
+            bodies_drawing: Default::default(),
             particles: Entity::DANGLING,
             handle: None,
         }
@@ -66,16 +73,6 @@ fn setup(state: &mut GameState, c: &mut EngineContext) {
     });
     particles_component.spawn_rate = None;
 
-    for (particle, body) in particles_component
-        .particles
-        .iter_mut()
-        .zip(bodies_drawing.into_iter())
-    {
-        particle.size = Vec2::splat(body.get_drawing_radius());
-        particle.color_start = body.color;
-        particle.color_end = body.color;
-    }
-
     world_mut()
         .insert(
             particles,
@@ -84,20 +81,24 @@ fn setup(state: &mut GameState, c: &mut EngineContext) {
         .unwrap();
     state.particles = particles;
 
+    let mut update_bodies = UpdateBodies::new(cosmic_system, &bodies, movements, 1.0);
     state.bodies = Arc::new(Mutex::new(bodies));
+    state.bodies_drawing = Arc::new(Mutex::new(bodies_drawing));
 
     let handle = {
         let bodies = Arc::clone(&state.bodies);
-        let mut update_bodies = UpdateBodies {
-            bounding_box: (&state.bounding_box).clone(),
-            cosmic_system,
-            forces: Vec::with_capacity(bodies.lock().len()),
-            movements,
-        };
+        let bodies_drawing = Arc::clone(&state.bodies_drawing);
 
         thread::spawn(move || loop {
             let mut bodies_lock = bodies.lock();
             update_bodies.update(&mut bodies_lock);
+            // Merged bodies got despawned, which moves the last index into the one of the absorbed body
+            let mut bodies_drawing_lock = bodies_drawing.lock();
+            for event in &update_bodies.events {
+                if let CollisionEvent::Merged { absorbed, .. } = event {
+                    bodies_drawing_lock.swap_remove(absorbed.body.index);
+                }
+            }
         })
     };
 
@@ -114,11 +115,22 @@ fn update(state: &mut GameState, _c: &mut EngineContext) {
             .unwrap();
         let inverse_world_size = 1.0 / (1.0 * simulation::AU);
         let bodies_lock = state.bodies.lock();
+        let bodies_drawing_lock = state.bodies_drawing.lock();
         for body in bodies_lock.iter() {
-            let particle = &mut particles.particles[body.index];
+            let Some(particle) = particles.particles.get_mut(body.index) else {
+                continue;
+            };
+            let drawing = &bodies_drawing_lock[body.index];
+            particle.size = Vec2::splat(drawing.get_drawing_radius());
+            particle.color_start = drawing.get_color();
+            particle.color_end = drawing.get_color();
             particle.lifetime_current = 500.;
             let position = body.position * inverse_world_size;
             particle.position = Vec2::new(position.x as f32, position.y as f32);
         }
+        // Bodies that got despawned
+        for particle in particles.particles.iter_mut().skip(bodies_lock.len()) {
+            particle.lifetime_current = 0.0;
+        }
     }
 }
@@ -0,0 +1,17 @@
+/// Decides when a node of the tree is far enough away to be treated as a single body.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OpeningCriterion {
+    /// Barnes-Hut: `width / distance < theta`
+    #[default]
+    Geometric,
+    /// The relative criterion from GADGET-2. Estimates the force error of a node with
+    /// `G * mass / distance^2 * (width / distance)^2` and compares it to the acceleration of the body in the previous step,
+    /// `alpha * |a_old|`.
+    /// Nodes that contain the body are always opened.
+    /// Falls back to [`OpeningCriterion::Geometric`] when the previous acceleration is unknown.
+    Relative { alpha: f64 },
+    /// `distance > width / theta + offset`, where `offset` is how far the center of mass of the node
+    /// is from the center of its cell. See Salmon & Warren (1994).
+    /// Catches the cases where most of the mass sits in a corner of a large cell.
+    CenterOfMassOffset,
+}
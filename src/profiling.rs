@@ -0,0 +1,23 @@
+//! Profiler zones that go to tracy with the `tracing` feature, and cost nothing without it.
+
+#[cfg(feature = "tracing")]
+pub use tracy_client;
+
+/// Profiler zone that lasts until the returned guard gets dropped.
+/// Only gets recorded when a tracy client is running.
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($name: expr) => {
+        $crate::profiling::tracy_client::Client::running()
+            .map(|client| client.span($crate::profiling::tracy_client::span_location!($name), 0))
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($name: expr) => {
+        None::<()>
+    };
+}
+
+pub(crate) use span;
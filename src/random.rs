@@ -0,0 +1,80 @@
+use glam::DVec3;
+
+/// Small and fast random number generator (SplitMix64), which gives the same numbers on every platform.
+/// Not suitable for anything that has to be unpredictable.
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        // https://prng.di.unimi.it/splitmix64.c
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[min, max)`
+    pub fn gen_range(&mut self, min: f64, max: f64) -> f64 {
+        // The upper 53 bits fit exactly into the mantissa
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        min + (max - min) * unit
+    }
+
+    /// Uniformly distributed in `[min, max)`
+    pub fn gen_usize(&mut self, min: usize, max: usize) -> usize {
+        assert!(min < max);
+        min + (self.next_u64() % (max - min) as u64) as usize
+    }
+
+    /// Box-Muller transform
+    /// https://en.wikipedia.org/wiki/Box%E2%80%93Muller_transform
+    pub fn gaussian(&mut self, mu: f64, sigma: f64) -> f64 {
+        // Uniformly distributed in (0, 1], so that the logarithm is finite
+        let u1 = 1.0 - self.gen_range(0.0, 1.0);
+        let u2 = self.gen_range(0.0, 1.0);
+
+        let mag = sigma * (-2.0 * u1.ln()).sqrt();
+        mag * (u2 * std::f64::consts::PI * 2.0).cos() + mu
+    }
+
+    /// Uniformly distributed direction
+    pub fn direction(&mut self) -> DVec3 {
+        loop {
+            let direction = DVec3::new(
+                self.gaussian(0., 1.),
+                self.gaussian(0., 1.),
+                self.gaussian(0., 1.),
+            );
+            if direction.length_squared() > 0.0 {
+                return direction.normalize();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        let mut random = Random::new(125245337);
+        let mut sum = 0.0;
+        for _ in 0..10000 {
+            let value = random.gen_range(-1.0, 3.0);
+            assert!((-1.0..3.0).contains(&value));
+            sum += value;
+            assert!(random.gen_usize(2, 5) < 5);
+        }
+        assert!((sum / 10000.0 - 1.0).abs() < 0.05, "{}", sum);
+        assert!((random.direction().length() - 1.0).abs() < 1e-12);
+    }
+}
@@ -1,24 +1,23 @@
 use crate::{
-    bounding_box::BoundingBox, celestial_body::CelestialBody,
-    celestial_body_extensions::CelestialBodyDrawing, cosmic_system::CosmicSystem,
+    bounding_box::BoundingBox,
+    celestial_body::CelestialBody,
+    celestial_body_extensions::{
+        CelestialBodyDrawing, BLUE, CYAN, DARK_GRAY, GREEN, MAGENTA, PINK, RED, WHITE,
+    },
+    collision::{self, CollisionEvent, CollisionMode},
+    cosmic_system::{BoundaryPolicy, CosmicSystem},
+    diagnostics::Diagnostics,
+    integrator::{Integrator, IntegratorKind},
+    profiling::span,
+    random::Random,
 };
-use comfy::{num_traits::Float, *};
 use glam::DVec3;
+use std::str::FromStr;
 pub const G: f64 = 6.6743e-11;
 pub const AU: f64 = 150e9;
 
-/// Box-Muller transform
-/// https://en.wikipedia.org/wiki/Box%E2%80%93Muller_transform
-fn random_gaussian(mu: f64, sigma: f64) -> f64 {
-    let mut u1 = gen_range(0.0, 1.0);
-    while u1 == 0.0 {
-        u1 = gen_range(0.0, 1.0);
-    }
-    let u2 = gen_range(0.0, 1.0);
-
-    let mag = sigma * (-2.0 * u1.ln()).sqrt();
-    mag * (u2 * std::f64::consts::PI * 2.0).cos() + mu
-}
+/// Seed of the scenarios, so that every run starts the same way
+const SEED: u64 = 125245337;
 
 pub struct CreateBodiesResult {
     pub cosmic_system: CosmicSystem,
@@ -28,32 +27,32 @@ pub struct CreateBodiesResult {
 }
 
 pub fn create_bodies(body_count: usize) -> CreateBodiesResult {
-    srand(125245337);
-    let predefined_colors = vec![RED, BLUE, CYAN, MAGENTA, PINK, GREEN, DARK_GRAY];
+    let mut random = Random::new(SEED);
+    let predefined_colors = [RED, BLUE, CYAN, MAGENTA, PINK, GREEN, DARK_GRAY];
     let mut bodies = Vec::with_capacity(body_count);
     let mut movements = Vec::with_capacity(body_count);
     let mut bodies_drawing = Vec::with_capacity(body_count);
     for i in 0..body_count {
         bodies.push(CelestialBody::new(
             i,
-            gen_range(5e20, 5e20 + 5e20),
+            random.gen_range(5e20, 5e20 + 5e20),
             DVec3::new(
-                (random_gaussian(0., 1.) * 8. - 4.) * 0.01 + if i % 2 == 0 { 2. } else { -2. },
-                (random_gaussian(0., 1.) * 8. - 4.) * 0.01,
-                (random_gaussian(0., 1.) * 8. - 4.) * 0.01,
+                (random.gaussian(0., 1.) * 8. - 4.) * 0.01 + if i % 2 == 0 { 2. } else { -2. },
+                (random.gaussian(0., 1.) * 8. - 4.) * 0.01,
+                (random.gaussian(0., 1.) * 8. - 4.) * 0.01,
             ) * crate::simulation::AU,
         ));
         movements.push(
             DVec3::new(
-                random_gaussian(0., 1.),
-                random_gaussian(0., 1.),
-                random_gaussian(0., 1.),
+                random.gaussian(0., 1.),
+                random.gaussian(0., 1.),
+                random.gaussian(0., 1.),
             ) * 1e9,
         );
 
         bodies_drawing.push(CelestialBodyDrawing {
-            radius: gen_range(10000., 800000.),
-            color: predefined_colors[random_usize(0, predefined_colors.len())],
+            radius: random.gen_range(10000., 800000.),
+            color: predefined_colors[random.gen_usize(0, predefined_colors.len())],
         });
     }
     bodies[0] = CelestialBody::new(0, 1e40, DVec3::ZERO);
@@ -79,42 +78,353 @@ pub fn create_bodies(body_count: usize) -> CreateBodiesResult {
     }
 }
 
+/// Plummer sphere in equilibrium, with the mass of the sun and a scale radius of 1 AU.
+/// Sampled like in Aarseth, Hénon & Wielen (1974), and cut off at 10 scale radii.
+pub fn create_plummer_sphere(body_count: usize) -> CreateBodiesResult {
+    let mut random = Random::new(SEED);
+    let total_mass = 2e30;
+    let scale_radius = AU;
+    let mass = total_mass / body_count as f64;
+
+    let mut bodies = Vec::with_capacity(body_count);
+    let mut movements = Vec::with_capacity(body_count);
+    for i in 0..body_count {
+        let radius = loop {
+            // Mass fraction within the radius, in (0, 1]
+            let mass_fraction = 1.0 - random.gen_range(0.0, 1.0);
+            let radius = scale_radius / (mass_fraction.powf(-2.0 / 3.0) - 1.0).sqrt();
+            if radius < 10.0 * scale_radius {
+                break radius;
+            }
+        };
+        bodies.push(CelestialBody::new(i, mass, random.direction() * radius));
+
+        // Fraction of the escape velocity, from the distribution q^2 * (1 - q^2)^(7/2)
+        let q = loop {
+            let q: f64 = random.gen_range(0.0, 1.0);
+            if random.gen_range(0.0, 0.1) < q * q * (1.0 - q * q).powf(3.5) {
+                break q;
+            }
+        };
+        let escape_velocity = (2.0 * G * total_mass).sqrt()
+            * (radius * radius + scale_radius * scale_radius).powf(-0.25);
+        movements.push(random.direction() * (q * escape_velocity));
+    }
+
+    create_result(bodies, movements, 20.0 * scale_radius, &mut random)
+}
+
+/// Uniform sphere at rest, with the mass of the sun and a radius of 1 AU, which collapses after a free fall time of about 65 days.
+pub fn create_cold_collapse(body_count: usize) -> CreateBodiesResult {
+    let mut random = Random::new(SEED);
+    let mass = 2e30 / body_count as f64;
+    let bodies = (0..body_count)
+        .map(|i| {
+            let mass_fraction = 1.0 - random.gen_range(0.0, 1.0);
+            let radius = AU * mass_fraction.cbrt();
+            CelestialBody::new(i, mass, random.direction() * radius)
+        })
+        .collect();
+
+    create_result(bodies, vec![DVec3::ZERO; body_count], 2.0 * AU, &mut random)
+}
+
+/// Bodies of the same size, and a tree that grows with them
+fn create_result(
+    bodies: Vec<CelestialBody>,
+    movements: Vec<DVec3>,
+    half_side_length: f64,
+    random: &mut Random,
+) -> CreateBodiesResult {
+    let predefined_colors = [RED, BLUE, CYAN, MAGENTA, PINK, GREEN, DARK_GRAY];
+    let bodies_drawing = bodies
+        .iter()
+        .map(|_| CelestialBodyDrawing {
+            radius: 1e6,
+            color: predefined_colors[random.gen_usize(0, predefined_colors.len())],
+        })
+        .collect();
+
+    let mut cosmic_system = CosmicSystem::new(
+        BoundingBox::from_center(DVec3::ZERO, DVec3::splat(half_side_length)),
+        bodies.len(),
+    );
+    cosmic_system.set_boundary_policy(BoundaryPolicy::Grow);
+
+    CreateBodiesResult {
+        cosmic_system,
+        bodies,
+        bodies_drawing,
+        movements,
+    }
+}
+
+/// Initial conditions that [`Scenario::create`] can set up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scenario {
+    /// [`create_bodies`]
+    TwoClusters,
+    /// [`create_plummer_sphere`]
+    Plummer,
+    /// [`create_cold_collapse`]
+    ColdCollapse,
+}
+
+impl Scenario {
+    pub const ALL: [Scenario; 3] = [
+        Scenario::TwoClusters,
+        Scenario::Plummer,
+        Scenario::ColdCollapse,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scenario::TwoClusters => "two-clusters",
+            Scenario::Plummer => "plummer",
+            Scenario::ColdCollapse => "cold-collapse",
+        }
+    }
+
+    pub fn create(self, body_count: usize) -> CreateBodiesResult {
+        match self {
+            Scenario::TwoClusters => create_bodies(body_count),
+            Scenario::Plummer => create_plummer_sphere(body_count),
+            Scenario::ColdCollapse => create_cold_collapse(body_count),
+        }
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Scenario::ALL
+            .into_iter()
+            .find(|scenario| scenario.name() == name)
+            .ok_or_else(|| format!("Unknown scenario {:?}", name))
+    }
+}
+
 #[derive(Clone)]
 pub struct UpdateBodies {
+    /// The bounding box of the tree in the last step.
+    /// Can change every step, depending on the [`crate::cosmic_system::BoundaryPolicy`].
     pub bounding_box: BoundingBox,
     pub cosmic_system: CosmicSystem,
+    /// Accelerations in m/s^2, indexed by [`CelestialBody::index`].
     pub forces: Vec<DVec3>,
+    /// Velocities in m/s, indexed by [`CelestialBody::index`].
     pub movements: Vec<DVec3>,
+    /// Timestep in seconds.
+    pub dt: f64,
+    /// Simulated time in seconds.
+    pub time: f64,
+    /// Number of updates so far.
+    pub step: u64,
+    pub integrator: IntegratorKind,
+    /// [`CelestialBody::id`] of the next spawned body.
+    pub next_id: u64,
+    pub collision_mode: CollisionMode,
+    /// What happened in the last update, in order.
+    pub events: Vec<CollisionEvent>,
+}
+
+/// A body that got removed with [`UpdateBodies::despawn`].
+#[derive(Clone, Copy, Debug)]
+pub struct Despawned {
+    pub body: CelestialBody,
+    pub velocity: DVec3,
 }
 
 impl UpdateBodies {
+    /// The bodies are only needed for their ids, so that spawned bodies get new ones.
+    pub fn new(
+        cosmic_system: CosmicSystem,
+        bodies: &[CelestialBody],
+        movements: Vec<DVec3>,
+        dt: f64,
+    ) -> Self {
+        Self {
+            next_id: bodies.iter().map(|body| body.id + 1).max().unwrap_or(0),
+            collision_mode: Default::default(),
+            events: vec![],
+            bounding_box: *cosmic_system.bounding_box(),
+            forces: Vec::with_capacity(movements.len()),
+            cosmic_system,
+            movements,
+            dt,
+            time: 0.0,
+            step: 0,
+            integrator: Default::default(),
+        }
+    }
+
     pub fn update(&mut self, bodies: &mut Vec<CelestialBody>) {
-        let cosmic_system = &mut self.cosmic_system;
-        {
-            let _span = span!("Update tree");
-            cosmic_system.set_all(bodies);
+        self.integrator.step(
+            &mut self.cosmic_system,
+            bodies,
+            &mut self.movements,
+            &mut self.forces,
+            self.dt,
+        );
+        self.time += self.dt;
+        self.step += 1;
+        self.events.clear();
+        match self.collision_mode {
+            CollisionMode::None => {}
+            CollisionMode::Merge => self.merge_collisions(bodies),
+            CollisionMode::Bounce { restitution } => self.bounce_collisions(bodies, restitution),
         }
+        self.bounding_box = *self.cosmic_system.bounding_box();
+    }
 
-        // for each body: compute the total force exerted on it.
-        // this is the bottleneck, but we only read things from the tree
-        // so we can easily multithread it
-        {
-            let _span = span!("Compute forces");
-            bodies
-                .par_iter()
-                .map(|body| cosmic_system.gravitational_force_zero_mass(&body, &bodies))
-                .collect_into_vec(&mut self.forces);
+    fn merge_collisions(&mut self, bodies: &mut Vec<CelestialBody>) {
+        let _span = span!("Collisions");
+        self.cosmic_system.set_all(bodies);
+        let merged = collision::merge_colliding(&self.cosmic_system, bodies, &mut self.movements);
+        for (survivor, absorbed) in merged {
+            let absorbed = self.despawn(bodies, absorbed).unwrap();
+            self.events
+                .push(CollisionEvent::Merged { survivor, absorbed });
         }
+    }
 
-        // move bodies with the force
-        // has to be done separately, because you can't move bodies while still computing gravity
-        {
-            let _span = span!("Update bodies");
-            for i in 0..bodies.len() {
-                let index = bodies[i].index;
-                self.movements[index] += self.forces[i];
-                bodies[i].update(self.movements[index]);
-            }
+    fn bounce_collisions(&mut self, bodies: &mut Vec<CelestialBody>, restitution: f64) {
+        let _span = span!("Collisions");
+        self.cosmic_system.set_all(bodies);
+        let bounced = collision::bounce_colliding(
+            &self.cosmic_system,
+            bodies,
+            &mut self.movements,
+            restitution,
+        );
+        if !bounced.is_empty() {
+            // The accelerations belong to the old positions
+            self.integrator.reset();
         }
+        self.events.extend(
+            bounced
+                .into_iter()
+                .map(|(a, b)| CollisionEvent::Bounced { a, b }),
+        );
+    }
+
+    /// Adds a body and returns its id. The index and the id of the body get overwritten.
+    /// Per-body data that is indexed by [`CelestialBody::index`] has to get the new body pushed to the end.
+    pub fn spawn(
+        &mut self,
+        bodies: &mut Vec<CelestialBody>,
+        mut body: CelestialBody,
+        velocity: DVec3,
+    ) -> u64 {
+        assert_eq!(bodies.len(), self.movements.len());
+        body.index = bodies.len();
+        body.id = self.next_id;
+        self.next_id += 1;
+        bodies.push(body);
+        self.movements.push(velocity);
+        if !self.forces.is_empty() {
+            self.forces.push(DVec3::ZERO);
+        }
+        // The integrator has to compute the acceleration of the new body before it can move on
+        self.integrator.reset();
+        body.id
+    }
+
+    /// Removes the body with the given id.
+    /// The body with the last index takes over the index of the removed body,
+    /// so per-body data that is indexed by [`CelestialBody::index`] stays in sync with
+    /// `data.swap_remove(despawned.body.index)`.
+    pub fn despawn(&mut self, bodies: &mut Vec<CelestialBody>, id: u64) -> Option<Despawned> {
+        assert_eq!(bodies.len(), self.movements.len());
+        let position = bodies.iter().position(|body| body.id == id)?;
+        let body = bodies.swap_remove(position);
+        let last_index = bodies.len();
+        if let Some(last) = bodies.iter_mut().find(|other| other.index == last_index) {
+            last.index = body.index;
+        }
+        let velocity = self.movements.swap_remove(body.index);
+        if self.forces.len() > body.index {
+            self.forces.swap_remove(body.index);
+        }
+        self.integrator.reset();
+        Some(Despawned { body, velocity })
+    }
+
+    /// Rebuilds the tree, which sorts the bodies, see [`Diagnostics::compute`].
+    /// The potential uses the opening criterion of the run, with the accelerations of the last step.
+    pub fn diagnostics(&mut self, bodies: &mut Vec<CelestialBody>) -> Diagnostics {
+        Diagnostics::compute(
+            bodies,
+            &self.movements,
+            &self.forces,
+            &mut self.cosmic_system,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_and_despawn() {
+        let mut bodies: Vec<_> = (0..4)
+            .map(|i| CelestialBody::new(i, 1e5, DVec3::new(i as f64, 0.0, 0.0)))
+            .collect();
+        let movements = (0..4).map(|i| DVec3::new(0.0, i as f64, 0.0)).collect();
+        let cosmic_system =
+            CosmicSystem::new(BoundingBox::new(DVec3::ONE * -10.0, DVec3::ONE * 10.0), 4);
+        let mut update_bodies = UpdateBodies::new(cosmic_system, &bodies, movements, 1.0);
+        update_bodies.update(&mut bodies);
+
+        let despawned = update_bodies.despawn(&mut bodies, 1).unwrap();
+        assert_eq!(despawned.body.id, 1);
+        assert!(update_bodies.despawn(&mut bodies, 1).is_none());
+        let id = update_bodies.spawn(
+            &mut bodies,
+            CelestialBody::new(0, 1e5, DVec3::new(0.0, 5.0, 0.0)),
+            DVec3::new(0.0, 10.0, 0.0),
+        );
+        assert_eq!(id, 4);
+        update_bodies.update(&mut bodies);
+
+        let mut indices: Vec<_> = bodies.iter().map(|body| body.index).collect();
+        indices.sort();
+        assert_eq!(indices, [0, 1, 2, 3]);
+        assert_eq!(update_bodies.movements.len(), 4);
+        for body in &bodies {
+            // Every body kept its own velocity, plus a tiny bit of gravity
+            let expected = if body.id == id { 10.0 } else { body.id as f64 };
+            let velocity = update_bodies.movements[body.index];
+            assert!(
+                (velocity.y - expected).abs() < 0.1,
+                "{:?} {}",
+                body,
+                velocity
+            );
+        }
+    }
+
+    #[test]
+    fn test_spawned_ids_are_new() {
+        // Like bodies from a snapshot, where the ids don't have to be dense
+        let mut bodies: Vec<_> = [10, 3]
+            .into_iter()
+            .enumerate()
+            .map(|(index, id)| CelestialBody {
+                id,
+                ..CelestialBody::new(index, 1e5, DVec3::new(index as f64, 0.0, 0.0))
+            })
+            .collect();
+        let cosmic_system =
+            CosmicSystem::new(BoundingBox::new(DVec3::ONE * -10.0, DVec3::ONE * 10.0), 2);
+        let mut update_bodies =
+            UpdateBodies::new(cosmic_system, &bodies, vec![DVec3::ZERO; 2], 1.0);
+
+        let body = CelestialBody::new(0, 1e5, DVec3::new(0.0, 5.0, 0.0));
+        assert_eq!(update_bodies.spawn(&mut bodies, body, DVec3::ZERO), 11);
+        update_bodies.despawn(&mut bodies, 11).unwrap();
+        assert_eq!(update_bodies.spawn(&mut bodies, body, DVec3::ZERO), 12);
     }
 }
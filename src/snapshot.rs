@@ -0,0 +1,324 @@
+//! The state of a run, in a binary file.
+//!
+//! Everything is little-endian, and floats are IEEE 754 doubles, so reading gives back the exact same bits.
+//!
+//! | Field          | Type       |                                                      |
+//! |----------------|------------|------------------------------------------------------|
+//! | magic          | `[u8; 8]`  | `COSMSNAP`                                           |
+//! | version        | `u32`      | [`VERSION`]                                          |
+//! | fields         | `u32`      | Bit 0: radius, bit 1: acceleration                   |
+//! | body count     | `u64`      |                                                      |
+//! | step           | `u64`      |                                                      |
+//! | time           | `f64`      | s                                                    |
+//! | G              | `f64`      | m^3 kg^-1 s^-2                                       |
+//! | bounding box   | `[f64; 6]` | min x, y, z, then max x, y, z in m                   |
+//!
+//! Followed by every body, ordered by id:
+//!
+//! | Field          | Type       |                                                      |
+//! |----------------|------------|------------------------------------------------------|
+//! | id             | `u64`      |                                                      |
+//! | mass           | `f64`      | kg                                                   |
+//! | position       | `[f64; 3]` | m                                                    |
+//! | velocity       | `[f64; 3]` | m/s                                                  |
+//! | radius         | `f64`      | m, only with bit 0 of the fields                     |
+//! | acceleration   | `[f64; 3]` | m/s^2, only with bit 1 of the fields                 |
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use glam::DVec3;
+
+use crate::{
+    bounding_box::BoundingBox,
+    celestial_body::CelestialBody,
+    simulation::{self, UpdateBodies},
+};
+
+pub const MAGIC: [u8; 8] = *b"COSMSNAP";
+pub const VERSION: u32 = 1;
+
+const RADIUS: u32 = 1 << 0;
+const ACCELERATION: u32 = 1 << 1;
+
+/// Which of the optional per-body fields get written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SnapshotFields {
+    pub radius: bool,
+    pub acceleration: bool,
+}
+
+impl SnapshotFields {
+    pub const ALL: Self = Self {
+        radius: true,
+        acceleration: true,
+    };
+
+    fn to_bits(self) -> u32 {
+        (if self.radius { RADIUS } else { 0 }) | (if self.acceleration { ACCELERATION } else { 0 })
+    }
+
+    fn from_bits(bits: u32) -> io::Result<Self> {
+        if bits & !(RADIUS | ACCELERATION) != 0 {
+            return Err(invalid_data(format!("Unknown snapshot fields {:#x}", bits)));
+        }
+        Ok(Self {
+            radius: bits & RADIUS != 0,
+            acceleration: bits & ACCELERATION != 0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SnapshotBody {
+    pub id: u64,
+    pub mass: f64,
+    pub position: DVec3,
+    pub velocity: DVec3,
+    /// 0 if it isn't in the snapshot
+    pub radius: f64,
+    /// 0 if it isn't in the snapshot
+    pub acceleration: DVec3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub step: u64,
+    /// In s
+    pub time: f64,
+    /// The gravitational constant that the run used
+    pub g: f64,
+    pub bounding_box: BoundingBox,
+    pub fields: SnapshotFields,
+    /// Ordered by id
+    pub bodies: Vec<SnapshotBody>,
+}
+
+impl Snapshot {
+    /// The current state of a run.
+    /// The accelerations are 0 if the integrator hasn't computed them yet.
+    pub fn capture(
+        update_bodies: &UpdateBodies,
+        bodies: &[CelestialBody],
+        fields: SnapshotFields,
+    ) -> Self {
+        let has_accelerations = update_bodies.forces.len() == bodies.len();
+        let mut snapshot_bodies: Vec<SnapshotBody> = bodies
+            .iter()
+            .map(|body| SnapshotBody {
+                id: body.id,
+                mass: body.mass,
+                position: body.position,
+                velocity: update_bodies.movements[body.index],
+                radius: if fields.radius { body.radius } else { 0.0 },
+                acceleration: if fields.acceleration && has_accelerations {
+                    update_bodies.forces[body.index]
+                } else {
+                    DVec3::ZERO
+                },
+            })
+            .collect();
+        snapshot_bodies.sort_by_key(|body| body.id);
+
+        Self {
+            step: update_bodies.step,
+            time: update_bodies.time,
+            g: simulation::G,
+            bounding_box: update_bodies.bounding_box,
+            fields,
+            bodies: snapshot_bodies,
+        }
+    }
+
+    /// The bodies and their velocities, with the indices in the order of the snapshot.
+    pub fn to_bodies(&self) -> (Vec<CelestialBody>, Vec<DVec3>) {
+        self.bodies
+            .iter()
+            .enumerate()
+            .map(|(index, snapshot_body)| {
+                let mut body =
+                    CelestialBody::new(index, snapshot_body.mass, snapshot_body.position);
+                body.id = snapshot_body.id;
+                body.radius = snapshot_body.radius;
+                (body, snapshot_body.velocity)
+            })
+            .unzip()
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.fields.to_bits().to_le_bytes())?;
+        writer.write_all(&(self.bodies.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.step.to_le_bytes())?;
+        write_f64(writer, self.time)?;
+        write_f64(writer, self.g)?;
+        write_vec3(writer, self.bounding_box.min)?;
+        write_vec3(writer, self.bounding_box.max)?;
+
+        for body in &self.bodies {
+            writer.write_all(&body.id.to_le_bytes())?;
+            write_f64(writer, body.mass)?;
+            write_vec3(writer, body.position)?;
+            write_vec3(writer, body.velocity)?;
+            if self.fields.radius {
+                write_f64(writer, body.radius)?;
+            }
+            if self.fields.acceleration {
+                write_vec3(writer, body.acceleration)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("Not a snapshot".to_string()));
+        }
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(invalid_data(format!(
+                "Unsupported snapshot version {}",
+                version
+            )));
+        }
+        let fields = SnapshotFields::from_bits(read_u32(reader)?)?;
+        let body_count = read_u64(reader)?;
+        let step = read_u64(reader)?;
+        let time = read_f64(reader)?;
+        let g = read_f64(reader)?;
+        let bounding_box = BoundingBox::new(read_vec3(reader)?, read_vec3(reader)?);
+
+        // Not trusting the count for the allocation, in case the file is broken
+        let mut bodies = Vec::with_capacity(body_count.min(1 << 20) as usize);
+        for _ in 0..body_count {
+            bodies.push(SnapshotBody {
+                id: read_u64(reader)?,
+                mass: read_f64(reader)?,
+                position: read_vec3(reader)?,
+                velocity: read_vec3(reader)?,
+                radius: if fields.radius {
+                    read_f64(reader)?
+                } else {
+                    0.0
+                },
+                acceleration: if fields.acceleration {
+                    read_vec3(reader)?
+                } else {
+                    DVec3::ZERO
+                },
+            });
+        }
+
+        Ok(Self {
+            step,
+            time,
+            g,
+            bounding_box,
+            fields,
+            bodies,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+pub(crate) fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn write_f64(writer: &mut impl Write, value: f64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_vec3(writer: &mut impl Write, value: DVec3) -> io::Result<()> {
+    for component in value.to_array() {
+        write_f64(writer, component)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_f64(reader: &mut impl Read) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_vec3(reader: &mut impl Read) -> io::Result<DVec3> {
+    Ok(DVec3::new(
+        read_f64(reader)?,
+        read_f64(reader)?,
+        read_f64(reader)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulation::create_plummer_sphere;
+
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let result = create_plummer_sphere(100);
+        let mut bodies = result.bodies;
+        let mut update_bodies =
+            UpdateBodies::new(result.cosmic_system, &bodies, result.movements, 3600.0);
+        for _ in 0..3 {
+            update_bodies.update(&mut bodies);
+        }
+
+        for fields in [SnapshotFields::default(), SnapshotFields::ALL] {
+            let snapshot = Snapshot::capture(&update_bodies, &bodies, fields);
+            assert_eq!(snapshot.step, 3);
+            let mut bytes = vec![];
+            snapshot.write(&mut bytes).unwrap();
+            let read = Snapshot::read(&mut bytes.as_slice()).unwrap();
+            // Compares the floats exactly
+            assert_eq!(read, snapshot);
+
+            let (restored, velocities) = read.to_bodies();
+            for (restored, velocity) in restored.iter().zip(velocities) {
+                let body = bodies.iter().find(|body| body.id == restored.id).unwrap();
+                assert_eq!(restored.position, body.position);
+                assert_eq!(velocity, update_bodies.movements[body.index]);
+            }
+        }
+
+        let mut bytes = vec![];
+        Snapshot::capture(&update_bodies, &bodies, SnapshotFields::ALL)
+            .write(&mut bytes)
+            .unwrap();
+        bytes[0] = b'X';
+        let error = Snapshot::read(&mut bytes.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        bytes[0] = MAGIC[0];
+        let error = Snapshot::read(&mut &bytes[..bytes.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
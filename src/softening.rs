@@ -0,0 +1,118 @@
+/// How gravity gets weakened at short distances, so that close encounters don't produce huge kicks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Softening {
+    /// Pure `1/r^2`
+    #[default]
+    None,
+    /// `1/(r^2 + epsilon^2)`, which never becomes exactly Newtonian.
+    Plummer { epsilon: f64 },
+    /// Cubic spline kernel from Monaghan & Lattanzio (1985), like in GADGET-2.
+    /// Exactly Newtonian beyond `h`. Roughly equivalent to a Plummer softening of `h / 2.8`.
+    CubicSpline { h: f64 },
+    /// Mass spread out over a sphere with a density of `1 - r^2/h^2`,
+    /// one of the compact kernels from Dehnen (2001). Exactly Newtonian beyond `h`.
+    Parabolic { h: f64 },
+}
+
+impl Softening {
+    /// The acceleration towards a body is `delta * mass * force_factor(delta.length_squared())`.
+    /// For Newtonian gravity, that is `1/r^3`.
+    #[inline]
+    pub fn force_factor(&self, distance_squared: f64) -> f64 {
+        match *self {
+            Softening::None => newtonian_force_factor(distance_squared),
+            Softening::Plummer { epsilon } => {
+                newtonian_force_factor(distance_squared + epsilon * epsilon)
+            }
+            Softening::CubicSpline { h } => {
+                if distance_squared >= h * h {
+                    return newtonian_force_factor(distance_squared);
+                }
+                let u = distance_squared.sqrt() / h;
+                let h_inv_3 = 1.0 / (h * h * h);
+                if u < 0.5 {
+                    h_inv_3 * (32.0 / 3.0 + u * u * (32.0 * u - 38.4))
+                } else {
+                    h_inv_3
+                        * (64.0 / 3.0 - 48.0 * u + 38.4 * u * u
+                            - 32.0 / 3.0 * u * u * u
+                            - 1.0 / 15.0 / (u * u * u))
+                }
+            }
+            Softening::Parabolic { h } => {
+                if distance_squared >= h * h {
+                    return newtonian_force_factor(distance_squared);
+                }
+                let u_squared = distance_squared / (h * h);
+                (2.5 - 1.5 * u_squared) / (h * h * h)
+            }
+        }
+    }
+
+    /// The potential of a body is `-mass * potential_factor(distance^2)`.
+    /// For Newtonian gravity, that is `1/r`.
+    #[inline]
+    pub fn potential_factor(&self, distance_squared: f64) -> f64 {
+        match *self {
+            Softening::None => 1.0 / distance_squared.sqrt(),
+            Softening::Plummer { epsilon } => 1.0 / (distance_squared + epsilon * epsilon).sqrt(),
+            Softening::CubicSpline { h } => {
+                if distance_squared >= h * h {
+                    return 1.0 / distance_squared.sqrt();
+                }
+                let u = distance_squared.sqrt() / h;
+                let kernel = if u < 0.5 {
+                    -2.8 + u * u * (16.0 / 3.0 + u * u * (6.4 * u - 9.6))
+                } else {
+                    -3.2 + 1.0 / (15.0 * u)
+                        + u * u * (32.0 / 3.0 + u * (-16.0 + u * (9.6 - 32.0 / 15.0 * u)))
+                };
+                -kernel / h
+            }
+            Softening::Parabolic { h } => {
+                if distance_squared >= h * h {
+                    return 1.0 / distance_squared.sqrt();
+                }
+                let u_squared = distance_squared / (h * h);
+                (15.0 / 8.0 - 1.25 * u_squared + 0.375 * u_squared * u_squared) / h
+            }
+        }
+    }
+}
+
+#[inline]
+fn newtonian_force_factor(distance_squared: f64) -> f64 {
+    1.0 / (distance_squared * distance_squared.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The compact kernels have to smoothly turn into Newtonian gravity at the cutoff.
+    #[test]
+    fn test_continuous_at_cutoff() {
+        let h = 2.0;
+        for softening in [Softening::CubicSpline { h }, Softening::Parabolic { h }] {
+            let inside = softening.force_factor(h * h * (1.0 - 1e-9));
+            let outside = Softening::None.force_factor(h * h);
+            assert!(
+                (inside - outside).abs() / outside < 1e-6,
+                "{:?}: {} vs {}",
+                softening,
+                inside,
+                outside
+            );
+
+            let inside = softening.potential_factor(h * h * (1.0 - 1e-9));
+            let outside = Softening::None.potential_factor(h * h);
+            assert!(
+                (inside - outside).abs() / outside < 1e-6,
+                "{:?}: {} vs {}",
+                softening,
+                inside,
+                outside
+            );
+        }
+    }
+}
@@ -2,9 +2,12 @@ use glam::DVec3;
 
 use crate::bounding_box::BoundingBox;
 
+/// Every axis gets scaled separately, so that the key uses its full resolution along each side of the box.
+/// Positions outside of the bounding box get clamped to its edges.
 pub fn z_order_curve(position: DVec3, bounding_box: &BoundingBox) -> u128 {
     let relative_position = position - bounding_box.min;
-    let scaled_position = relative_position * (u32::MAX as f64 / bounding_box.side_length());
+    let scaled_position =
+        (relative_position * scale(bounding_box)).clamp(DVec3::ZERO, DVec3::splat(u32::MAX as f64));
     let x = scaled_position.x as u32;
     let y = scaled_position.y as u32;
     let z = scaled_position.z as u32;
@@ -22,9 +25,36 @@ pub fn z_order_curve(position: DVec3, bounding_box: &BoundingBox) -> u128 {
     result << 32
 }
 
+/// Factor from a position relative to the bounding box to the u32 coordinates of the key.
+/// Flat sides of the box get a factor of 0, so that everything on them is at coordinate 0.
+#[inline]
+pub fn scale(bounding_box: &BoundingBox) -> DVec3 {
+    let extent = bounding_box.extent();
+    DVec3::from_array(extent.to_array().map(|side_length| {
+        if side_length > 0.0 {
+            u32::MAX as f64 / side_length
+        } else {
+            0.0
+        }
+    }))
+}
+
+/// Turns a key back into the scaled x, y and z coordinates
+pub fn z_order_curve_inverse(key: u128) -> [u32; 3] {
+    let key = key >> 32;
+    let mut result = [0u32; 3];
+    for i in 0u32..32 {
+        for (axis, coordinate) in result.iter_mut().enumerate() {
+            let bit = (key >> (3 * i + axis as u32)) & 1;
+            *coordinate |= (bit as u32) << i;
+        }
+    }
+    result
+}
+
 pub fn _z_order_curve_slow(position: DVec3, bounding_box: &BoundingBox) -> u128 {
     let relative_position = (position - bounding_box.min).max(DVec3::ZERO);
-    let scaled_position = relative_position * (u32::MAX as f64 / bounding_box.side_length());
+    let scaled_position = relative_position * scale(bounding_box);
     let x = scaled_position.x as u32;
     let y = scaled_position.y as u32;
     let z = scaled_position.z as u32;
@@ -54,5 +84,28 @@ mod tests {
         // so we should get something like 100 010 110 110 110 110 ...
         let result = z_order_curve(position, &bounding_box);
         assert_eq!(result, 0b100010110110110110110110110110110110110110110110110110110110110110110110110110110110110110110110u128 << 32);
+        assert_eq!(
+            z_order_curve_inverse(result),
+            [0, u32::MAX / 2, 0b1011 << 28 | ((1 << 28) - 1)]
+        );
+    }
+
+    #[test]
+    fn test_z_order_curve_rectangular() {
+        // A flat box still uses the full resolution along its short side
+        let bounding_box = BoundingBox::new(DVec3::ZERO, DVec3::new(100.0, 100.0, 1.0));
+        let position = DVec3::new(0., 50.0, 0.75);
+        assert_eq!(
+            z_order_curve(position, &bounding_box),
+            z_order_curve(
+                DVec3::new(0., 0.5, 0.75),
+                &BoundingBox::new(DVec3::ZERO, DVec3::ONE)
+            )
+        );
+
+        // A box without any height puts everything at z = 0
+        let bounding_box = BoundingBox::new(DVec3::ZERO, DVec3::new(1.0, 1.0, 0.0));
+        let key = z_order_curve(DVec3::new(1.0, 1.0, 0.0), &bounding_box);
+        assert_eq!(z_order_curve_inverse(key), [u32::MAX, u32::MAX, 0]);
     }
 }